@@ -0,0 +1,74 @@
+//! One `demo_mlp` training epoch (500 moon samples, 2 -> 16 -> 16 -> 1, svm loss + L2) on the
+//! `Value` graph and on the `Tape`. Run with `cargo bench`.
+
+use micrograd_rs::nn::Module;
+use micrograd_rs::tape::Tape;
+use micrograd_rs::{MLP, MakeMoonDataset, Value};
+use std::time::{Duration, Instant};
+
+const EPOCHS: u32 = 5;
+const ALPHA: f64 = 0.0001;
+
+fn value_epoch(mlp: &MLP, dataset: &MakeMoonDataset) -> f64 {
+    let mut loss = Value::new(0.0);
+    for idx in 0..dataset.len() {
+        let (x, label) = dataset.get(idx);
+        let out = mlp.forward(&x);
+        let current_loss = (&Value::new(1.0) - &(&out[0] * label)).relu();
+        loss = &loss + &current_loss;
+    }
+    loss = &loss / dataset.len() as f64;
+    for p in mlp.parameters().iter() {
+        loss = &loss + &(&(&p.value() * &p.value()) * ALPHA);
+    }
+    mlp.zero_grad();
+    loss.backward();
+    loss.data()
+}
+
+fn tape_epoch(mlp: &MLP, dataset: &MakeMoonDataset, tape: &mut Tape) -> f64 {
+    tape.clear();
+    let params = mlp.parameters();
+    let vars = tape.load(&params);
+    let mut loss = tape.var(0.0);
+    for idx in 0..dataset.len() {
+        let (x, label) = dataset.get(idx);
+        let x = x.iter().map(|v| tape.var(v.data())).collect::<Vec<_>>();
+        let out = mlp.forward_tape(&vars, &x);
+        loss = loss + (1.0 - out[0] * label).relu();
+    }
+    loss = loss / dataset.len() as f64;
+    for v in vars.iter() {
+        loss = loss + *v * *v * ALPHA;
+    }
+    mlp.zero_grad();
+    loss.backward().accumulate(&params, &vars);
+    loss.data()
+}
+
+fn time(mut f: impl FnMut() -> f64) -> (Duration, f64) {
+    let start = Instant::now();
+    let mut loss = 0.0;
+    for _ in 0..EPOCHS {
+        loss = f();
+    }
+    (start.elapsed() / EPOCHS, loss)
+}
+
+fn main() {
+    let mlp = MLP::new(2, &[16, 16, 1]);
+    let dataset = MakeMoonDataset::new(500);
+    let mut tape = Tape::new();
+
+    let (value_time, value_loss) = time(|| value_epoch(&mlp, &dataset));
+    let (tape_time, tape_loss) = time(|| tape_epoch(&mlp, &dataset, &mut tape));
+    assert!((value_loss - tape_loss).abs() < 1e-9);
+
+    println!("demo_mlp epoch, {} nodes on the tape", tape.len());
+    println!("  Value: {:>10.3?} / epoch", value_time);
+    println!("  Tape:  {:>10.3?} / epoch", tape_time);
+    println!(
+        "  speedup: {:.1}x",
+        value_time.as_secs_f64() / tape_time.as_secs_f64()
+    );
+}
@@ -0,0 +1,48 @@
+//! Trains a character-level model on a list of names and samples new ones.
+//!
+//! cargo run --release --example makemore -- [names.txt] [context]
+
+use micrograd_rs::Result;
+use micrograd_rs::makemore::{CharModel, Vocab, read_words};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .unwrap_or_else(|| "examples/names.txt".to_string());
+    let context = args.next().and_then(|c| c.parse().ok()).unwrap_or(3);
+
+    let words = read_words(&path)?;
+    let vocab = Vocab::new(&words);
+    let examples = vocab.examples(&words, context)?;
+    println!(
+        "{} words, {} chars, {} examples",
+        words.len(),
+        vocab.len(),
+        examples.len()
+    );
+
+    let model = CharModel::try_new(vocab.len(), context, 8, &[32])?;
+    let mut rng = StdRng::seed_from_u64(42);
+    let steps = 500;
+    for chunk in 0..steps / 100 {
+        let lr = if chunk < steps / 200 { 0.2 } else { 0.05 };
+        let losses = model.fit(&examples, 100, 32, lr, &mut rng)?;
+        println!(
+            "step {}: loss {:.4}",
+            (chunk + 1) * 100,
+            losses.iter().sum::<f64>() / losses.len() as f64
+        );
+    }
+    println!("full loss: {:.4}", model.loss(&examples)?.data());
+
+    for temperature in [0.5, 1.0] {
+        println!("temperature {}:", temperature);
+        for _ in 0..10 {
+            println!("  {}", model.sample(&vocab, temperature, 20, &mut rng)?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Anomaly detection: report the first NaN or infinity instead of letting it propagate.
+//!
+//! When enabled, every op checks the data of the node it creates and `backward` checks every
+//! gradient it writes. The report names the node and the chain of ops that produced it, e.g.
+//! `pow <- - <- leaf(x)` when `pow` took the ln of a negative base.
+//! The mode is per thread. `parallel::data_parallel_backward` passes it on to its workers,
+//! other threads have to enable it themselves.
+
+use crate::ValueInner;
+use crate::error::Error;
+use std::cell::Cell;
+use std::sync::{Arc, RwLock};
+
+thread_local! {
+    static DETECT_ANOMALY: Cell<bool> = const { Cell::new(false) };
+}
+
+// how many ops of the history are reported
+const MAX_CHAIN_LEN: usize = 16;
+
+pub fn set_detect_anomaly(enabled: bool) {
+    DETECT_ANOMALY.with(|flag| flag.set(enabled));
+}
+
+pub fn is_anomaly_detection_enabled() -> bool {
+    DETECT_ANOMALY.with(|flag| flag.get())
+}
+
+/// Runs `f` with anomaly detection enabled, then restores the previous mode.
+pub fn detect_anomaly<R>(f: impl FnOnce() -> R) -> R {
+    // restores the mode even if `f` panics on an anomaly
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_detect_anomaly(self.0);
+        }
+    }
+
+    let _restore = Restore(is_anomaly_detection_enabled());
+    set_detect_anomaly(true);
+    f()
+}
+
+pub(crate) fn label(inner: &ValueInner) -> String {
+    let op = if inner._op.is_empty() {
+        "leaf"
+    } else {
+        &inner._op
+    };
+    if inner.name.is_empty() {
+        op.to_string()
+    } else {
+        format!("{}({})", op, inner.name)
+    }
+}
+
+/// Ops from `node` up to a leaf, following the first non-finite parent if there is one.
+pub(crate) fn op_chain(node: &Arc<RwLock<ValueInner>>) -> Vec<String> {
+    let mut chain = vec![];
+    let mut current = node.clone();
+    while chain.len() < MAX_CHAIN_LEN {
+        let next = {
+            let inner = current.read().unwrap();
+            chain.push(label(&inner));
+            inner
+                ._prev
+                .iter()
+                .find(|p| !p.0.read().unwrap().data.is_finite())
+                .or(inner._prev.first())
+                .map(|p| p.0.clone())
+        };
+        match next {
+            Some(next) => current = next,
+            None => break,
+        }
+    }
+    chain
+}
+
+/// Error for a non-finite `data` in `node`, created by the forward pass.
+pub(crate) fn check_forward(node: &Arc<RwLock<ValueInner>>) -> Result<(), Error> {
+    let data = node.read().unwrap().data;
+    if data.is_finite() {
+        return Ok(());
+    }
+    Err(Error::NonFinite {
+        stage: "forward".to_string(),
+        node: label(&node.read().unwrap()),
+        value: data,
+        chain: op_chain(node),
+    })
+}
+
+/// Error for a non-finite `grad` in `node`, written by the backward of `producer`.
+pub(crate) fn check_backward(
+    node: &Arc<RwLock<ValueInner>>,
+    producer: &Arc<RwLock<ValueInner>>,
+) -> Result<(), Error> {
+    let grad = node.read().unwrap().grad;
+    if grad.is_finite() {
+        return Ok(());
+    }
+    Err(Error::NonFinite {
+        stage: "backward".to_string(),
+        node: label(&node.read().unwrap()),
+        value: grad,
+        chain: op_chain(producer),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackwardOptions, Value};
+
+    #[test]
+    fn forward_anomaly_reports_op_chain() {
+        let x = Value::new_with_name(-2.0, "x".to_string());
+        let y = &x - 1.0;
+        let result = std::panic::catch_unwind(|| detect_anomaly(|| y.pow(0.5)));
+        let panic = result.unwrap_err();
+        assert_eq!(
+            panic.downcast_ref::<String>().unwrap(),
+            "non-finite forward value NaN at pow, produced by: pow <- - <- leaf(x)"
+        );
+        assert!(!is_anomaly_detection_enabled());
+
+        // off by default, the NaN is silently created
+        let z = y.pow(0.5);
+        assert!(z.data().is_nan());
+        match check_forward(&z.0).unwrap_err() {
+            Error::NonFinite { node, chain, .. } => {
+                assert_eq!(node, "pow");
+                assert_eq!(chain, vec!["pow", "-", "leaf(x)"]);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn backward_anomaly_is_returned_by_try_backward() {
+        let x = Value::new_with_name(0.0, "x".to_string());
+        let y = x.pow(0.5);
+        let retain = BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        };
+        assert!(y.try_backward_with_options(retain).is_ok());
+
+        x.set_grad(0.0);
+        let err = detect_anomaly(|| y.try_backward()).unwrap_err();
+        match err {
+            Error::NonFinite {
+                stage, node, chain, ..
+            } => {
+                assert_eq!(stage, "backward");
+                assert_eq!(node, "leaf(x)");
+                assert_eq!(chain, vec!["pow".to_string(), "leaf(x)".to_string()]);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn forward_anomaly_message() {
+        let x = Value::new_with_name(0.0, "x".to_string());
+        let y = &Value::constant(1.0) / &x;
+        let err = check_forward(&y.0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-finite forward value inf at *, produced by: * <- pow <- leaf(x)"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{Value, ValueInner};
+use ndarray::Array2;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+/// Gradients of `output` with respect to each of `inputs`.
+///
+/// Unlike `Value::backward` this does not touch the `grad` fields: the gradients are returned
+/// as new `Value`s. With `create_graph` they are graphs over the original nodes, so they can be
+/// differentiated again (Hessian-vector products, gradient penalties, Newton steps...).
+/// Without it they are detached leaves. Inputs `output` does not depend on get a zero.
+/// Panics when the graph of `output` was freed by a backward without `retain_graph`.
+pub fn grad(output: &Value, inputs: &[&Value], create_graph: bool) -> Vec<Value> {
+    let mut topo = Vec::new();
+    output.build_topo(&mut topo, &mut HashSet::new(), true);
+    crate::check_not_freed(&topo).unwrap_or_else(|e| panic!("{}", e));
+
+    let mut grads: HashMap<*const RwLock<ValueInner>, Value> = HashMap::new();
+    grads.insert(Arc::as_ptr(&output.0), Value::new(1.0));
+
+    for node in topo.iter().rev() {
+        let Some(g) = grads.get(&Arc::as_ptr(node)).map(|g| g.clone()) else {
+            continue;
+        };
+        let (grad_fn, prev) = {
+            let inner = node.read().unwrap();
+            let prev = inner._prev.iter().map(|p| p.value()).collect::<Vec<_>>();
+            (inner._grad_fn.clone(), prev)
+        };
+        let Some(grad_fn) = grad_fn else {
+            continue;
+        };
+
+        for (p, pg) in prev.iter().zip(grad_fn(&prev, &g)) {
+            if !p.requires_grad() {
+                continue;
+            }
+            let acc = match grads.remove(&Arc::as_ptr(&p.0)) {
+                Some(acc) => &acc + &pg,
+                None => pg,
+            };
+            grads.insert(Arc::as_ptr(&p.0), acc);
+        }
+    }
+
+    inputs
+        .iter()
+        .map(|input| match grads.get(&Arc::as_ptr(&input.0)) {
+            Some(g) if create_graph => g.clone(),
+            Some(g) => Value::new(g.data()),
+            None => Value::new(0.0),
+        })
+        .collect()
+}
+
+/// Jacobian of `f` at `x`, `out[[i, j]]` is `d f(x)[i] / d x[j]`.
+pub fn jacobian<F>(f: F, x: &[Value]) -> Array2<f64>
+where
+    F: Fn(&[Value]) -> Vec<Value>,
+{
+    let inputs = x.iter().collect::<Vec<_>>();
+    let outputs = f(x);
+    let mut jac = Array2::zeros((outputs.len(), x.len()));
+    for (i, out) in outputs.iter().enumerate() {
+        for (j, g) in grad(out, &inputs, false).iter().enumerate() {
+            jac[[i, j]] = g.data();
+        }
+    }
+    jac
+}
+
+/// Hessian of the scalar function `f` at `x`, `out[[i, j]]` is `d2 f(x) / d x[i] d x[j]`.
+pub fn hessian<F>(f: F, x: &[Value]) -> Array2<f64>
+where
+    F: Fn(&[Value]) -> Value,
+{
+    let inputs = x.iter().collect::<Vec<_>>();
+    let first = grad(&f(x), &inputs, true);
+    let mut hess = Array2::zeros((x.len(), x.len()));
+    for (i, g) in first.iter().enumerate() {
+        for (j, gg) in grad(g, &inputs, false).iter().enumerate() {
+            hess[[i, j]] = gg.data();
+        }
+    }
+    hess
+}
+
+/// Hessian-vector product `H v` of the scalar function `f` at `x`, without building `H`.
+pub fn hvp<F>(f: F, x: &[Value], v: &[f64]) -> Vec<f64>
+where
+    F: Fn(&[Value]) -> Value,
+{
+    assert_eq!(x.len(), v.len(), "x and v must have the same length");
+    let inputs = x.iter().collect::<Vec<_>>();
+    let first = grad(&f(x), &inputs, true);
+    let mut dot = Value::new(0.0);
+    for (g, v) in first.iter().zip(v.iter()) {
+        dot = &dot + &(g * *v);
+    }
+    grad(&dot, &inputs, false)
+        .iter()
+        .map(|g| g.data())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn grad_matches_backward() {
+        let x = Value::new(1.5);
+        let y = Value::new(-0.5);
+        let z = &(&(&x * &y) + &x.pow(3.0)) - &(&y.exp() / &x);
+
+        let g = grad(&z, &[&x, &y], false);
+        z.backward();
+        assert_close(g[0].data(), x.grad());
+        assert_close(g[1].data(), y.grad());
+    }
+
+    #[test]
+    fn second_derivative() {
+        // f = x^3 * y, df/dx = 3x^2 y, d2f/dx2 = 6xy, d2f/dxdy = 3x^2
+        let x = Value::new(2.0);
+        let y = Value::new(3.0);
+        let f = &x.pow(3.0) * &y;
+
+        let dx = grad(&f, &[&x], true).remove(0);
+        assert_close(dx.data(), 36.0);
+        let d2 = grad(&dx, &[&x, &y], false);
+        assert_close(d2[0].data(), 36.0);
+        assert_close(d2[1].data(), 12.0);
+
+        // backward through a gradient graph fills the leaf grads
+        dx.backward();
+        assert_close(x.grad(), 36.0);
+        assert_close(y.grad(), 12.0);
+    }
+
+    #[test]
+    fn jacobian_test() {
+        let (r, theta) = (2.0, 0.5);
+        let x = [Value::new(r), Value::new(theta)];
+        // f = [r * t, r * t^2]
+        let jac = jacobian(|x| vec![&x[0] * &x[1], &x[0] * &x[1].pow(2.0)], &x);
+        assert_eq!(jac.shape(), [2, 2]);
+        assert_close(jac[[0, 0]], theta);
+        assert_close(jac[[0, 1]], r);
+        assert_close(jac[[1, 0]], theta * theta);
+        assert_close(jac[[1, 1]], 2.0 * r * theta);
+    }
+
+    #[test]
+    fn hessian_and_hvp() {
+        // f = x^2 y + y^3, H = [[2y, 2x], [2x, 6y]]
+        let f = |x: &[Value]| &(&x[0].pow(2.0) * &x[1]) + &x[1].pow(3.0);
+        let x = [Value::new(1.5), Value::new(-2.0)];
+
+        let hess = hessian(f, &x);
+        assert_close(hess[[0, 0]], -4.0);
+        assert_close(hess[[0, 1]], 3.0);
+        assert_close(hess[[1, 0]], 3.0);
+        assert_close(hess[[1, 1]], -12.0);
+
+        let hv = hvp(f, &x, &[1.0, 2.0]);
+        assert_close(hv[0], -4.0 + 6.0);
+        assert_close(hv[1], 3.0 - 24.0);
+    }
+
+    #[test]
+    fn unreachable_input_has_zero_grad() {
+        let x = Value::new(2.0);
+        let y = Value::new(3.0);
+        let f = x.ln();
+        let g = grad(&f, &[&x, &y], true);
+        assert_close(g[0].data(), 0.5);
+        assert_close(g[1].data(), 0.0);
+    }
+
+    #[test]
+    fn second_derivative_of_activations() {
+        let x = Value::new(0.7);
+        let (t, s) = (0.7f64.tanh(), 1.0 / (1.0 + (-0.7f64).exp()));
+
+        let dt = grad(&x.tanh(), &[&x], true).remove(0);
+        assert_close(grad(&dt, &[&x], false)[0].data(), -2.0 * t * (1.0 - t * t));
+        let ds = grad(&x.sigmoid(), &[&x], true).remove(0);
+        assert_close(ds.data(), s * (1.0 - s));
+        assert_close(
+            grad(&ds, &[&x], false)[0].data(),
+            s * (1.0 - s) * (1.0 - 2.0 * s),
+        );
+    }
+}
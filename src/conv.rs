@@ -0,0 +1,762 @@
+//! Convolution and pooling layers.
+//!
+//! Values are passed flat, channel by channel and row by row (`c * h * w + y * w + x` for an
+//! image), the spatial size of the input is fixed when the layer is built so the layers can be
+//! chained as any other `Module`, e.g. in a `Sequential`.
+
+use crate::error::{Error, Result};
+use crate::nn::Module;
+use crate::{Prev, Value};
+use rand::Rng;
+use std::sync::Arc;
+
+/// Stride, zero padding and dilation, the same along every spatial axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConvOptions {
+    pub stride: usize,
+    pub padding: usize,
+    /// Spacing between the kernel taps, 1 for a dense kernel.
+    pub dilation: usize,
+}
+
+impl Default for ConvOptions {
+    fn default() -> Self {
+        ConvOptions {
+            stride: 1,
+            padding: 0,
+            dilation: 1,
+        }
+    }
+}
+
+impl ConvOptions {
+    /// Output length of one axis, `None` when the kernel does not fit.
+    fn output_len(&self, input: usize, kernel: usize) -> Option<usize> {
+        let span = self.dilation * kernel.checked_sub(1)? + 1;
+        let padded = input + 2 * self.padding;
+        (self.stride > 0 && padded >= span).then(|| (padded - span) / self.stride + 1)
+    }
+
+    /// Input position of the tap `k` of the output position `o`, `None` in the padding.
+    fn input_pos(&self, o: usize, k: usize, input: usize) -> Option<usize> {
+        (o * self.stride + k * self.dilation)
+            .checked_sub(self.padding)
+            .filter(|&i| i < input)
+    }
+}
+
+fn output_size(
+    options: &ConvOptions,
+    input: (usize, usize),
+    kernel: (usize, usize),
+) -> Result<(usize, usize)> {
+    if kernel.0 == 0 || kernel.1 == 0 || options.stride == 0 || options.dilation == 0 {
+        return Err(Error::InvalidArgument(format!(
+            "kernel {:?} with {:?}: kernel size, stride and dilation must be positive",
+            kernel, options
+        )));
+    }
+    match (
+        options.output_len(input.0, kernel.0),
+        options.output_len(input.1, kernel.1),
+    ) {
+        (Some(h), Some(w)) => Ok((h, w)),
+        _ => Err(Error::InvalidArgument(format!(
+            "kernel {:?} with {:?} does not fit an input of size {:?}",
+            kernel, options, input
+        ))),
+    }
+}
+
+fn check_channels(in_channels: usize, out_channels: usize) -> Result<()> {
+    if in_channels == 0 || out_channels == 0 {
+        return Err(Error::InvalidArgument(format!(
+            "{} input and {} output channels, both must be positive",
+            in_channels, out_channels
+        )));
+    }
+    Ok(())
+}
+
+fn check_input(x: &[Value], expected: usize) -> Result<()> {
+    if x.len() != expected {
+        return Err(Error::InputSize {
+            expected,
+            got: x.len(),
+        });
+    }
+    Ok(())
+}
+
+fn sum(terms: Vec<Value>) -> Value {
+    let mut terms = terms.into_iter();
+    let mut out = terms.next().unwrap_or_else(|| Value::constant(0.0));
+    for t in terms {
+        out = &out + &t;
+    }
+    out
+}
+
+fn init_weights(n: usize, fan_in: usize) -> Vec<Value> {
+    let mut rng = rand::rng();
+    let bound = 1.0 / (fan_in as f64).sqrt();
+    (0..n)
+        .map(|_| Value::new(rng.random_range(-bound..=bound)))
+        .collect()
+}
+
+/// 2-D convolution over an image of `in_channels` channels of `input_size` (height, width).
+pub struct Conv2d {
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: (usize, usize),
+    input_size: (usize, usize),
+    output_size: (usize, usize),
+    options: ConvOptions,
+    // [out][in][ky][kx]
+    weight: Vec<Value>,
+    bias: Vec<Value>,
+}
+
+impl Conv2d {
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: (usize, usize),
+        input_size: (usize, usize),
+    ) -> Conv2d {
+        Conv2d::new_with_options(
+            in_channels,
+            out_channels,
+            kernel_size,
+            input_size,
+            ConvOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: (usize, usize),
+        input_size: (usize, usize),
+        options: ConvOptions,
+    ) -> Conv2d {
+        Conv2d::try_new_with_options(in_channels, out_channels, kernel_size, input_size, options)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Errors when a channel count, the kernel size, the stride or the dilation is 0, or when
+    /// the kernel does not fit the padded input.
+    pub fn try_new_with_options(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: (usize, usize),
+        input_size: (usize, usize),
+        options: ConvOptions,
+    ) -> Result<Conv2d> {
+        check_channels(in_channels, out_channels)?;
+        let output_size = output_size(&options, input_size, kernel_size)?;
+        let fan_in = in_channels * kernel_size.0 * kernel_size.1;
+        Ok(Conv2d {
+            in_channels,
+            out_channels,
+            kernel_size,
+            input_size,
+            output_size,
+            options,
+            weight: init_weights(out_channels * fan_in, fan_in),
+            bias: init_weights(out_channels, fan_in),
+        })
+    }
+
+    /// (height, width) of each output channel.
+    pub fn output_size(&self) -> (usize, usize) {
+        self.output_size
+    }
+
+    pub fn out_channels(&self) -> usize {
+        self.out_channels
+    }
+
+    fn weight(&self, o: usize, c: usize, ky: usize, kx: usize) -> &Value {
+        let (kh, kw) = self.kernel_size;
+        &self.weight[((o * self.in_channels + c) * kh + ky) * kw + kx]
+    }
+}
+
+impl Module for Conv2d {
+    fn parameters(&self) -> Vec<Prev> {
+        self.weight
+            .iter()
+            .chain(self.bias.iter())
+            .map(|p| Prev(p.0.clone()))
+            .collect()
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        let (h, w) = self.input_size;
+        let (oh, ow) = self.output_size;
+        let (kh, kw) = self.kernel_size;
+        check_input(x, self.in_channels * h * w)?;
+
+        let mut out = Vec::with_capacity(self.out_channels * oh * ow);
+        for o in 0..self.out_channels {
+            for oy in 0..oh {
+                for ox in 0..ow {
+                    let mut terms = vec![self.bias[o].clone()];
+                    for ky in 0..kh {
+                        let Some(iy) = self.options.input_pos(oy, ky, h) else {
+                            continue;
+                        };
+                        for kx in 0..kw {
+                            let Some(ix) = self.options.input_pos(ox, kx, w) else {
+                                continue;
+                            };
+                            for c in 0..self.in_channels {
+                                terms.push(self.weight(o, c, ky, kx) * &x[(c * h + iy) * w + ix]);
+                            }
+                        }
+                    }
+                    out.push(sum(terms));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// 1-D convolution over a sequence of `in_channels` channels of `input_len` steps.
+pub struct Conv1d {
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    input_len: usize,
+    output_len: usize,
+    options: ConvOptions,
+    // [out][in][k]
+    weight: Vec<Value>,
+    bias: Vec<Value>,
+}
+
+impl Conv1d {
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        input_len: usize,
+    ) -> Conv1d {
+        Conv1d::new_with_options(
+            in_channels,
+            out_channels,
+            kernel_size,
+            input_len,
+            ConvOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        input_len: usize,
+        options: ConvOptions,
+    ) -> Conv1d {
+        Conv1d::try_new_with_options(in_channels, out_channels, kernel_size, input_len, options)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// See `Conv2d::try_new_with_options`.
+    pub fn try_new_with_options(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        input_len: usize,
+        options: ConvOptions,
+    ) -> Result<Conv1d> {
+        check_channels(in_channels, out_channels)?;
+        let output_len = output_size(&options, (1, input_len), (1, kernel_size))?.1;
+        let fan_in = in_channels * kernel_size;
+        Ok(Conv1d {
+            in_channels,
+            out_channels,
+            kernel_size,
+            input_len,
+            output_len,
+            options,
+            weight: init_weights(out_channels * fan_in, fan_in),
+            bias: init_weights(out_channels, fan_in),
+        })
+    }
+
+    /// Length of each output channel.
+    pub fn output_len(&self) -> usize {
+        self.output_len
+    }
+
+    pub fn out_channels(&self) -> usize {
+        self.out_channels
+    }
+}
+
+impl Module for Conv1d {
+    fn parameters(&self) -> Vec<Prev> {
+        self.weight
+            .iter()
+            .chain(self.bias.iter())
+            .map(|p| Prev(p.0.clone()))
+            .collect()
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        let (n, k) = (self.input_len, self.kernel_size);
+        check_input(x, self.in_channels * n)?;
+
+        let mut out = Vec::with_capacity(self.out_channels * self.output_len);
+        for o in 0..self.out_channels {
+            for t in 0..self.output_len {
+                let mut terms = vec![self.bias[o].clone()];
+                for j in 0..k {
+                    let Some(i) = self.options.input_pos(t, j, n) else {
+                        continue;
+                    };
+                    for c in 0..self.in_channels {
+                        let w = &self.weight[(o * self.in_channels + c) * k + j];
+                        terms.push(w * &x[c * n + i]);
+                    }
+                }
+                out.push(sum(terms));
+            }
+        }
+        Ok(out)
+    }
+}
+
+// windows of a 2-D pooling, the inputs of each output position that are not in the padding
+fn pool_windows<'a>(
+    x: &'a [Value],
+    channels: usize,
+    input: (usize, usize),
+    output: (usize, usize),
+    kernel: usize,
+    options: &ConvOptions,
+) -> Vec<Vec<&'a Value>> {
+    let (h, w) = input;
+    let mut windows = Vec::with_capacity(channels * output.0 * output.1);
+    for c in 0..channels {
+        for oy in 0..output.0 {
+            for ox in 0..output.1 {
+                let mut window = Vec::with_capacity(kernel * kernel);
+                for ky in 0..kernel {
+                    let Some(iy) = options.input_pos(oy, ky, h) else {
+                        continue;
+                    };
+                    for kx in 0..kernel {
+                        if let Some(ix) = options.input_pos(ox, kx, w) {
+                            window.push(&x[(c * h + iy) * w + ix]);
+                        }
+                    }
+                }
+                windows.push(window);
+            }
+        }
+    }
+    windows
+}
+
+/// Max over each `kernel_size` x `kernel_size` window, the stride defaults to the kernel size.
+/// The gradient goes to the max of each window, padding never wins.
+pub struct MaxPool2d {
+    channels: usize,
+    kernel_size: usize,
+    input_size: (usize, usize),
+    output_size: (usize, usize),
+    options: ConvOptions,
+}
+
+impl MaxPool2d {
+    pub fn new(channels: usize, kernel_size: usize, input_size: (usize, usize)) -> MaxPool2d {
+        let options = ConvOptions {
+            stride: kernel_size,
+            ..Default::default()
+        };
+        MaxPool2d::new_with_options(channels, kernel_size, input_size, options)
+    }
+
+    pub fn new_with_options(
+        channels: usize,
+        kernel_size: usize,
+        input_size: (usize, usize),
+        options: ConvOptions,
+    ) -> MaxPool2d {
+        MaxPool2d::try_new_with_options(channels, kernel_size, input_size, options)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// See `Conv2d::try_new_with_options`, the padding is also limited to half of the kernel
+    /// size so that no window is only padding.
+    pub fn try_new_with_options(
+        channels: usize,
+        kernel_size: usize,
+        input_size: (usize, usize),
+        options: ConvOptions,
+    ) -> Result<MaxPool2d> {
+        check_channels(channels, channels)?;
+        if options.padding * 2 > kernel_size {
+            return Err(Error::InvalidArgument(format!(
+                "padding {} is more than half of the kernel size {}",
+                options.padding, kernel_size
+            )));
+        }
+        Ok(MaxPool2d {
+            channels,
+            kernel_size,
+            input_size,
+            output_size: output_size(&options, input_size, (kernel_size, kernel_size))?,
+            options,
+        })
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        self.output_size
+    }
+}
+
+impl Module for MaxPool2d {
+    fn parameters(&self) -> Vec<Prev> {
+        vec![]
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        let (h, w) = self.input_size;
+        check_input(x, self.channels * h * w)?;
+        let windows = pool_windows(
+            x,
+            self.channels,
+            self.input_size,
+            self.output_size,
+            self.kernel_size,
+            &self.options,
+        );
+        Ok(windows
+            .iter()
+            .map(|window| {
+                window
+                    .iter()
+                    .copied()
+                    .reduce(|a, b| if b.data() > a.data() { b } else { a })
+                    .map(max_of)
+                    .unwrap_or_else(|| Value::constant(0.0))
+            })
+            .collect())
+    }
+}
+
+// output node of a max pooling window won by `winner`, its gradient goes to the winner
+fn max_of(winner: &Value) -> Value {
+    let out = Value::from_op(winner.data(), "maxpool", &[winner]);
+    out.0.write().unwrap()._grad_fn = Some(Arc::new(|_, g| vec![g.clone()]));
+
+    let winner_weak = Arc::downgrade(&winner.0);
+    let out_weak = Arc::downgrade(&out.0);
+    out.0.write().unwrap()._backward = Some(Arc::new(move || {
+        if let (Some(winner_rc), Some(out_rc)) = (winner_weak.upgrade(), out_weak.upgrade()) {
+            let out_grad = out_rc.read().unwrap().grad;
+            winner_rc.write().unwrap().add_grad(out_grad);
+        }
+    }));
+    out
+}
+
+/// Mean over each `kernel_size` x `kernel_size` window, the stride defaults to the kernel size.
+/// Padding counts as zeros.
+pub struct AvgPool2d {
+    channels: usize,
+    kernel_size: usize,
+    input_size: (usize, usize),
+    output_size: (usize, usize),
+    options: ConvOptions,
+}
+
+impl AvgPool2d {
+    pub fn new(channels: usize, kernel_size: usize, input_size: (usize, usize)) -> AvgPool2d {
+        let options = ConvOptions {
+            stride: kernel_size,
+            ..Default::default()
+        };
+        AvgPool2d::new_with_options(channels, kernel_size, input_size, options)
+    }
+
+    pub fn new_with_options(
+        channels: usize,
+        kernel_size: usize,
+        input_size: (usize, usize),
+        options: ConvOptions,
+    ) -> AvgPool2d {
+        AvgPool2d::try_new_with_options(channels, kernel_size, input_size, options)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// See `Conv2d::try_new_with_options`.
+    pub fn try_new_with_options(
+        channels: usize,
+        kernel_size: usize,
+        input_size: (usize, usize),
+        options: ConvOptions,
+    ) -> Result<AvgPool2d> {
+        check_channels(channels, channels)?;
+        Ok(AvgPool2d {
+            channels,
+            kernel_size,
+            input_size,
+            output_size: output_size(&options, input_size, (kernel_size, kernel_size))?,
+            options,
+        })
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        self.output_size
+    }
+}
+
+impl Module for AvgPool2d {
+    fn parameters(&self) -> Vec<Prev> {
+        vec![]
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        let (h, w) = self.input_size;
+        check_input(x, self.channels * h * w)?;
+        let windows = pool_windows(
+            x,
+            self.channels,
+            self.input_size,
+            self.output_size,
+            self.kernel_size,
+            &self.options,
+        );
+        let area = (self.kernel_size * self.kernel_size) as f64;
+        Ok(windows
+            .into_iter()
+            .map(|window| &sum(window.into_iter().map(|v| v.clone()).collect()) / area)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(data: &[f64]) -> Vec<Value> {
+        data.iter().map(|&v| Value::new(v)).collect()
+    }
+
+    fn set(params: &[Prev], data: &[f64]) {
+        for (p, d) in params.iter().zip(data.iter()) {
+            p.value().set_data(*d);
+        }
+    }
+
+    #[test]
+    fn conv2d_stride_padding_dilation() {
+        // 1 channel 3x3 input, 2x2 kernel of ones, zero bias
+        let x = values(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        let conv = Conv2d::new(1, 1, (2, 2), (3, 3));
+        set(&conv.parameters(), &[1.0, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(conv.output_size(), (2, 2));
+        let out = conv
+            .forward(&x)
+            .iter()
+            .map(|v| v.data())
+            .collect::<Vec<_>>();
+        assert_eq!(out, vec![12.0, 16.0, 24.0, 28.0]);
+
+        let options = ConvOptions {
+            stride: 2,
+            padding: 1,
+            dilation: 1,
+        };
+        let conv = Conv2d::new_with_options(1, 1, (2, 2), (3, 3), options);
+        set(&conv.parameters(), &[1.0, 1.0, 1.0, 1.0, 0.0]);
+        let out = conv
+            .forward(&x)
+            .iter()
+            .map(|v| v.data())
+            .collect::<Vec<_>>();
+        assert_eq!(out, vec![1.0, 5.0, 11.0, 28.0]);
+
+        let options = ConvOptions {
+            dilation: 2,
+            ..Default::default()
+        };
+        let conv = Conv2d::new_with_options(1, 1, (2, 2), (3, 3), options);
+        set(&conv.parameters(), &[1.0, 1.0, 1.0, 1.0, 0.5]);
+        assert_eq!(conv.output_size(), (1, 1));
+        assert_eq!(conv.forward(&x)[0].data(), 1.0 + 3.0 + 7.0 + 9.0 + 0.5);
+        assert!(conv.try_forward(&x[..8]).is_err());
+    }
+
+    #[test]
+    fn conv_grads_match_numeric() {
+        let raw = (0..2 * 4 * 4)
+            .map(|i| (i as f64 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let options = ConvOptions {
+            stride: 2,
+            padding: 1,
+            dilation: 1,
+        };
+        let conv = Conv2d::new_with_options(2, 3, (3, 3), (4, 4), options);
+        let loss = |x: &Vec<Value>| {
+            let out = conv.forward(x);
+            sum(out
+                .iter()
+                .enumerate()
+                .map(|(i, o)| o * (i as f64 * 0.1 - 0.5))
+                .collect())
+        };
+        let x = values(&raw);
+        loss(&x).backward();
+
+        let h = 1e-6;
+        for (i, xi) in x.iter().enumerate() {
+            let (mut plus, mut minus) = (raw.clone(), raw.clone());
+            plus[i] += h;
+            minus[i] -= h;
+            let numeric = (loss(&values(&plus)).data() - loss(&values(&minus)).data()) / (2.0 * h);
+            assert!((xi.grad() - numeric).abs() < 1e-6);
+        }
+        for p in conv.parameters().iter().step_by(5) {
+            let p = p.value();
+            let d = p.data();
+            p.set_data(d + h);
+            let plus = loss(&values(&raw)).data();
+            p.set_data(d - h);
+            let minus = loss(&values(&raw)).data();
+            p.set_data(d);
+            assert!((p.grad() - (plus - minus) / (2.0 * h)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn conv1d_test() {
+        // 2 channels of length 4, kernel 2 with dilation 2
+        let x = values(&[1.0, 2.0, 3.0, 4.0, 10.0, 20.0, 30.0, 40.0]);
+        let options = ConvOptions {
+            dilation: 2,
+            ..Default::default()
+        };
+        let conv = Conv1d::new_with_options(2, 1, 2, 4, options);
+        set(&conv.parameters(), &[1.0, 1.0, 0.5, -0.5, 0.0]);
+        assert_eq!(conv.output_len(), 2);
+        let out = conv.forward(&x);
+        assert_eq!(out[0].data(), 1.0 + 3.0 + 5.0 - 15.0);
+        assert_eq!(out[1].data(), 2.0 + 4.0 + 10.0 - 20.0);
+
+        out[1].backward();
+        assert_eq!(x[5].grad(), 0.5);
+        assert_eq!(x[0].grad(), 0.0);
+    }
+
+    #[test]
+    fn pooling() {
+        let x = values(&[
+            1.0, 2.0, 5.0, 6.0, //
+            3.0, 4.0, 8.0, 7.0, //
+            -1.0, -2.0, 0.0, 0.0, //
+            -3.0, -4.0, 0.0, 1.0,
+        ]);
+        let max = MaxPool2d::new(1, 2, (4, 4));
+        let out = max.forward(&x);
+        assert_eq!(
+            out.iter().map(|v| v.data()).collect::<Vec<_>>(),
+            vec![4.0, 8.0, -1.0, 1.0]
+        );
+        assert_eq!(out[1].op(), "maxpool");
+        assert!(Arc::ptr_eq(&out[1].parents()[0].0, &x[6].0));
+        out[1].backward();
+        assert_eq!(x[6].grad(), 1.0);
+        assert_eq!(x[7].grad(), 0.0);
+
+        let avg = AvgPool2d::new(1, 2, (4, 4));
+        let out = avg.forward(&x);
+        assert_eq!(
+            out.iter().map(|v| v.data()).collect::<Vec<_>>(),
+            vec![2.5, 6.5, -2.5, 0.25]
+        );
+
+        // padding counts as zeros for the mean and never wins the max
+        let options = ConvOptions {
+            stride: 2,
+            padding: 1,
+            dilation: 1,
+        };
+        let avg = AvgPool2d::new_with_options(1, 2, (4, 4), options);
+        assert_eq!(avg.output_size(), (3, 3));
+        assert_eq!(avg.forward(&x)[0].data(), 0.25);
+        let max = MaxPool2d::new_with_options(1, 2, (4, 4), options);
+        assert_eq!(max.forward(&x)[6].data(), -3.0);
+
+        // overlapping windows won by the same input add up their gradients
+        let max = MaxPool2d::new_with_options(1, 2, (4, 4), ConvOptions::default());
+        let out = max.forward(&x);
+        sum(out.iter().take(3).map(|v| v.clone()).collect()).backward();
+        assert_eq!(x[6].grad(), 1.0 + 2.0);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        let zero_stride = ConvOptions {
+            stride: 0,
+            ..Default::default()
+        };
+        let zero_dilation = ConvOptions {
+            dilation: 0,
+            ..Default::default()
+        };
+        let invalid = |r: Result<()>| matches!(r, Err(Error::InvalidArgument(_)));
+        let default = ConvOptions::default();
+        assert!(invalid(
+            Conv2d::try_new_with_options(1, 1, (0, 2), (3, 3), default).map(|_| ())
+        ));
+        assert!(invalid(
+            Conv2d::try_new_with_options(1, 1, (2, 2), (3, 3), zero_stride).map(|_| ())
+        ));
+        assert!(invalid(
+            Conv2d::try_new_with_options(1, 1, (4, 4), (3, 3), default).map(|_| ())
+        ));
+        assert!(invalid(
+            Conv1d::try_new_with_options(1, 1, 0, 3, default).map(|_| ())
+        ));
+        assert!(invalid(
+            Conv1d::try_new_with_options(1, 1, 2, 3, zero_dilation).map(|_| ())
+        ));
+        assert!(invalid(
+            MaxPool2d::try_new_with_options(1, 0, (4, 4), default).map(|_| ())
+        ));
+        let wide_padding = ConvOptions {
+            padding: 2,
+            ..Default::default()
+        };
+        assert!(invalid(
+            MaxPool2d::try_new_with_options(1, 2, (4, 4), wide_padding).map(|_| ())
+        ));
+        assert!(invalid(
+            AvgPool2d::try_new_with_options(1, 2, (4, 4), zero_stride).map(|_| ())
+        ));
+        assert!(AvgPool2d::try_new_with_options(1, 2, (4, 4), default).is_ok());
+
+        // no channel means a zero fan-in, checked before the weights are drawn
+        assert!(invalid(
+            Conv2d::try_new_with_options(0, 1, (2, 2), (3, 3), default).map(|_| ())
+        ));
+        assert!(invalid(
+            Conv2d::try_new_with_options(1, 0, (2, 2), (3, 3), default).map(|_| ())
+        ));
+        assert!(invalid(
+            Conv1d::try_new_with_options(0, 1, 2, 3, default).map(|_| ())
+        ));
+        assert!(invalid(
+            MaxPool2d::try_new_with_options(0, 2, (4, 4), default).map(|_| ())
+        ));
+    }
+}
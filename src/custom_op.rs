@@ -0,0 +1,132 @@
+use crate::Value;
+use std::sync::Arc;
+
+/// A differentiable op defined outside of the crate, see `Value::apply`.
+///
+/// The op works on plain `f64`s: `forward` computes the output from the input data and
+/// `backward` the gradient of every input given the gradient of the output.
+pub trait CustomOp: Send + Sync {
+    /// Recorded as the op of the output node.
+    fn name(&self) -> &str;
+
+    fn forward(&self, inputs: &[f64]) -> f64;
+
+    /// One gradient per input, for an output gradient of `out_grad`.
+    /// Must be linear in `out_grad`, as any chain rule is.
+    fn backward(&self, inputs: &[f64], output: f64, out_grad: f64) -> Vec<f64>;
+}
+
+impl Value {
+    /// Applies `op` to `inputs` and records it in the graph like a built-in op.
+    ///
+    /// `autograd::grad` with `create_graph` can differentiate the result once more with
+    /// respect to the upstream gradient only: the local derivatives of a custom op are
+    /// constants for the gradient graph.
+    pub fn apply<O: CustomOp + 'static>(op: O, inputs: &[&Value]) -> Value {
+        let data = inputs.iter().map(|v| v.data()).collect::<Vec<_>>();
+        let out = Value::from_op(op.forward(&data), op.name(), inputs);
+        let op = Arc::new(op);
+
+        let grad_op = op.clone();
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(move |prev, g| {
+            let data = prev.iter().map(|v| v.data()).collect::<Vec<_>>();
+            let output = grad_op.forward(&data);
+            grad_op
+                .backward(&data, output, 1.0)
+                .into_iter()
+                .map(|local| g * local)
+                .collect()
+        }));
+
+        let inputs_weak = inputs
+            .iter()
+            .map(|v| Arc::downgrade(&v.0))
+            .collect::<Vec<_>>();
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
+            let (Some(inputs_rc), Some(out_rc)) = (
+                inputs_weak
+                    .iter()
+                    .map(|w| w.upgrade())
+                    .collect::<Option<Vec<_>>>(),
+                out_weak.upgrade(),
+            ) else {
+                return;
+            };
+            let (output, out_grad) = {
+                let out = out_rc.read().unwrap();
+                (out.data, out.grad)
+            };
+            let data = inputs_rc
+                .iter()
+                .map(|rc| rc.read().unwrap().data)
+                .collect::<Vec<_>>();
+            let grads = op.backward(&data, output, out_grad);
+            for (rc, grad) in inputs_rc.iter().zip(grads) {
+                rc.write().unwrap().add_grad(grad);
+            }
+        }));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BackwardOptions;
+    use crate::autograd::grad;
+
+    struct Softplus;
+
+    impl CustomOp for Softplus {
+        fn name(&self) -> &str {
+            "softplus"
+        }
+
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0].exp().ln_1p()
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64, out_grad: f64) -> Vec<f64> {
+            vec![out_grad / (1.0 + (-inputs[0]).exp())]
+        }
+    }
+
+    // x * y + x, two inputs to check the gradients are routed in order
+    struct MulAdd;
+
+    impl CustomOp for MulAdd {
+        fn name(&self) -> &str {
+            "mul_add"
+        }
+
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0] * inputs[1] + inputs[0]
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64, out_grad: f64) -> Vec<f64> {
+            vec![out_grad * (inputs[1] + 1.0), out_grad * inputs[0]]
+        }
+    }
+
+    #[test]
+    fn custom_op_backward() {
+        let x = Value::new(2.0);
+        let y = Value::new(-3.0);
+        let z = Value::apply(MulAdd, &[&x, &y]);
+        let out = &Value::apply(Softplus, &[&z]) * 2.0;
+        assert!(format!("{:?}", z).contains("mul_add"));
+
+        out.backward_with_options(BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        });
+        let s = 1.0 / (1.0 + (-z.data()).exp());
+        assert!((x.grad() - 2.0 * s * -2.0).abs() < 1e-12);
+        assert!((y.grad() - 2.0 * s * 2.0).abs() < 1e-12);
+
+        let g = grad(&out, &[&x, &y], false);
+        assert!((g[0].data() - x.grad()).abs() < 1e-12);
+        assert!((g[1].data() - y.grad()).abs() < 1e-12);
+    }
+}
@@ -1,8 +1,11 @@
 use crate::Value;
+use crate::error::{Error, Result};
 use ndarray::{Array, Axis, Ix1, Ix2, concatenate, stack};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 // file from :https://github.com/samsja/rusty-grad , thanks samsja
 
-pub fn make_moon(n_samples: usize) -> [Array<f32, Ix2>; 2] {
+pub fn make_moon(n_samples: usize) -> Result<[Array<f32, Ix2>; 2]> {
     let n_samples_in = n_samples / 2;
     let n_samples_out = n_samples - n_samples_in;
 
@@ -14,10 +17,10 @@ pub fn make_moon(n_samples: usize) -> [Array<f32, Ix2>; 2] {
     let in_circ_x = Array::linspace(0., pi, n_samples_in).mapv(|x| 1. - x.cos());
     let in_circ_y = Array::linspace(0., pi, n_samples_in).mapv(|x| 1. - x.sin() - 0.5);
 
-    let out_circ = stack(Axis(0), &[out_circ_x.view(), out_circ_y.view()]).unwrap();
-    let in_circ = stack(Axis(0), &[in_circ_x.view(), in_circ_y.view()]).unwrap();
+    let out_circ = stack(Axis(0), &[out_circ_x.view(), out_circ_y.view()])?;
+    let in_circ = stack(Axis(0), &[in_circ_x.view(), in_circ_y.view()])?;
 
-    [out_circ, in_circ]
+    Ok([out_circ, in_circ])
 }
 
 pub struct MakeMoonDataset {
@@ -27,34 +30,309 @@ pub struct MakeMoonDataset {
 
 impl MakeMoonDataset {
     pub fn new(n_samples: usize) -> MakeMoonDataset {
-        let [out_circ, in_circ] = make_moon(n_samples);
+        Self::try_new(n_samples).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(n_samples: usize) -> Result<MakeMoonDataset> {
+        if n_samples == 0 {
+            return Err(Error::InvalidArgument(
+                "n_samples must be positive".to_string(),
+            ));
+        }
+        let [out_circ, in_circ] = make_moon(n_samples)?;
 
-        let data = concatenate(Axis(1), &[in_circ.view(), out_circ.view()]).unwrap();
+        let data = concatenate(Axis(1), &[in_circ.view(), out_circ.view()])?;
 
         let label_out = Array::<f32, Ix1>::zeros(out_circ.shape()[1]);
         let label_in = Array::<f32, Ix1>::ones(in_circ.shape()[1]);
 
-        let label =
-            (concatenate(Axis(0), &[label_in.view(), label_out.view()]).unwrap() - 0.5) * 2.0;
+        let label = (concatenate(Axis(0), &[label_in.view(), label_out.view()])? - 0.5) * 2.0;
 
-        MakeMoonDataset { data, label }
+        Ok(MakeMoonDataset { data, label })
     }
 
     pub fn len(&self) -> usize {
         self.data.shape()[1]
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn get(&self, idx: usize) -> (Vec<Value>, f64) {
-        let data = self.data.column(idx).to_shape((2, 1)).unwrap().mapv(|x| x);
+        self.try_get(idx).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get(&self, idx: usize) -> Result<(Vec<Value>, f64)> {
+        if idx >= self.len() {
+            return Err(Error::IndexOutOfRange {
+                index: idx,
+                len: self.len(),
+            });
+        }
+        let data = self.data.column(idx).to_shape((2, 1))?.mapv(|x| x);
         let (x, _) = data.into_raw_vec_and_offset();
         let x = x
             .iter()
             .map(|x| {
                 let x = *x as f64;
-                Value::new(x)
+                Value::constant(x)
             })
             .collect();
-        (x, self.label[idx] as f64)
+        Ok((x, self.label[idx] as f64))
+    }
+}
+
+/// How `ClassificationDataset::get_as` encodes the class of a sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelFormat {
+    /// The class index, for `loss::cross_entropy`.
+    Index,
+    /// 1.0 at the class index and 0.0 elsewhere.
+    OneHot,
+    /// -1.0 for class 0 and 1.0 for class 1, for a max-margin loss on a single output.
+    /// Only for two classes.
+    Sign,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Label {
+    Index(usize),
+    OneHot(Vec<f64>),
+    Sign(f64),
+}
+
+/// A dataset of feature vectors, each labelled with one of `n_classes` classes.
+pub trait ClassificationDataset {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn n_classes(&self) -> usize;
+
+    /// The features of a sample and its class index.
+    fn try_sample(&self, idx: usize) -> Result<(Vec<Value>, usize)>;
+
+    fn sample(&self, idx: usize) -> (Vec<Value>, usize) {
+        self.try_sample(idx).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// The features of a sample and its label in `format`.
+    fn try_get_as(&self, idx: usize, format: LabelFormat) -> Result<(Vec<Value>, Label)> {
+        let (x, class) = self.try_sample(idx)?;
+        let label = match format {
+            LabelFormat::Index => Label::Index(class),
+            LabelFormat::OneHot => {
+                let mut one_hot = vec![0.0; self.n_classes()];
+                one_hot[class] = 1.0;
+                Label::OneHot(one_hot)
+            }
+            LabelFormat::Sign if self.n_classes() == 2 => {
+                Label::Sign(if class == 1 { 1.0 } else { -1.0 })
+            }
+            LabelFormat::Sign => {
+                return Err(Error::InvalidArgument(format!(
+                    "sign labels need 2 classes, the dataset has {}",
+                    self.n_classes()
+                )));
+            }
+        };
+        Ok((x, label))
+    }
+
+    fn get_as(&self, idx: usize, format: LabelFormat) -> (Vec<Value>, Label) {
+        self.try_get_as(idx, format)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+impl ClassificationDataset for MakeMoonDataset {
+    fn len(&self) -> usize {
+        MakeMoonDataset::len(self)
+    }
+
+    /// Class 1 is the inner moon, labelled 1.0 by `get`.
+    fn n_classes(&self) -> usize {
+        2
+    }
+
+    fn try_sample(&self, idx: usize) -> Result<(Vec<Value>, usize)> {
+        let (x, label) = self.try_get(idx)?;
+        Ok((x, if label > 0.0 { 1 } else { 0 }))
+    }
+}
+
+// a 2-D point from the standard normal distribution, Box-Muller
+fn normal_2d(rng: &mut StdRng) -> (f64, f64) {
+    let u1 = 1.0 - rng.random::<f64>();
+    let u2 = rng.random::<f64>();
+    let r = (-2.0 * u1.ln()).sqrt();
+    let theta = 2.0 * std::f64::consts::PI * u2;
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// 2-D points of a seeded generator, `n_classes` classes with the same number of samples.
+/// When `n_samples` is not a multiple of `n_classes` the first classes get one more sample.
+pub struct PointsDataset {
+    points: Vec<[f64; 2]>,
+    pub label: Vec<usize>,
+    n_classes: usize,
+}
+
+impl PointsDataset {
+    /// Gaussian blobs of standard deviation `std`, centered on a circle of radius 2.
+    pub fn blobs(n_samples: usize, n_classes: usize, std: f64, seed: u64) -> Result<PointsDataset> {
+        PointsDataset::generate(n_samples, n_classes, seed, |rng, class, _| {
+            let angle = 2.0 * std::f64::consts::PI * class as f64 / n_classes as f64;
+            let (dx, dy) = normal_2d(rng);
+            [2.0 * angle.cos() + std * dx, 2.0 * angle.sin() + std * dy]
+        })
+    }
+
+    /// Interleaved spiral arms, one per class, as in the cs231n example.
+    pub fn spirals(n_samples: usize, n_classes: usize, seed: u64) -> Result<PointsDataset> {
+        PointsDataset::generate(n_samples, n_classes, seed, |rng, class, t| {
+            let angle = 4.0 * (class as f64 + t) + 0.2 * normal_2d(rng).0;
+            [t * angle.sin(), t * angle.cos()]
+        })
+    }
+
+    // `point(rng, class, t)` with `t` going from 0 to 1 over the samples of a class
+    fn generate<F>(n_samples: usize, n_classes: usize, seed: u64, point: F) -> Result<PointsDataset>
+    where
+        F: Fn(&mut StdRng, usize, f64) -> [f64; 2],
+    {
+        if n_classes == 0 || n_samples < n_classes {
+            return Err(Error::InvalidArgument(format!(
+                "{} samples for {} classes",
+                n_samples, n_classes
+            )));
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut points = Vec::with_capacity(n_samples);
+        let mut label = Vec::with_capacity(n_samples);
+        for class in 0..n_classes {
+            let per_class = n_samples / n_classes + usize::from(class < n_samples % n_classes);
+            for i in 0..per_class {
+                let t = i as f64 / (per_class.max(2) - 1) as f64;
+                points.push(point(&mut rng, class, t));
+                label.push(class);
+            }
+        }
+        Ok(PointsDataset {
+            points,
+            label,
+            n_classes,
+        })
+    }
+}
+
+impl ClassificationDataset for PointsDataset {
+    fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    fn n_classes(&self) -> usize {
+        self.n_classes
+    }
+
+    fn try_sample(&self, idx: usize) -> Result<(Vec<Value>, usize)> {
+        let point = self.points.get(idx).ok_or(Error::IndexOutOfRange {
+            index: idx,
+            len: self.points.len(),
+        })?;
+        Ok((
+            point.iter().map(|&p| Value::constant(p)).collect(),
+            self.label[idx],
+        ))
+    }
+}
+
+/// 8x8 images of handwritten digits, e.g. the UCI optdigits files: one image per line, the 64
+/// pixels row by row (0 to 16) and then the digit, comma separated. Pixels are scaled to [0, 1].
+pub struct DigitsDataset {
+    data: Vec<Vec<f64>>,
+    pub label: Vec<usize>,
+}
+
+impl DigitsDataset {
+    pub const SIDE: usize = 8;
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<DigitsDataset> {
+        let text = std::fs::read_to_string(path)?;
+        let n_pixels = Self::SIDE * Self::SIDE;
+        let mut data = vec![];
+        let mut label = vec![];
+        for (i, line) in text
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+        {
+            let fields = line
+                .split(',')
+                .map(|f| f.trim().parse::<f64>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| Error::InvalidArgument(format!("line {}: {}", i + 1, e)))?;
+            if fields.len() != n_pixels + 1 {
+                return Err(Error::InvalidArgument(format!(
+                    "line {}: expected {} fields, got {}",
+                    i + 1,
+                    n_pixels + 1,
+                    fields.len()
+                )));
+            }
+            let digit = fields[n_pixels];
+            if digit.fract() != 0.0 || !(0.0..10.0).contains(&digit) {
+                return Err(Error::InvalidArgument(format!(
+                    "line {}: label {} is not a digit",
+                    i + 1,
+                    digit
+                )));
+            }
+            data.push(fields[..n_pixels].iter().map(|p| p / 16.0).collect());
+            label.push(digit as usize);
+        }
+        Ok(DigitsDataset { data, label })
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The 64 pixels of an image, row by row, and its digit.
+    pub fn get(&self, idx: usize) -> (Vec<Value>, usize) {
+        self.try_get(idx).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get(&self, idx: usize) -> Result<(Vec<Value>, usize)> {
+        if idx >= self.len() {
+            return Err(Error::IndexOutOfRange {
+                index: idx,
+                len: self.len(),
+            });
+        }
+        let x = self.data[idx].iter().map(|&p| Value::constant(p)).collect();
+        Ok((x, self.label[idx]))
+    }
+}
+
+impl ClassificationDataset for DigitsDataset {
+    fn len(&self) -> usize {
+        DigitsDataset::len(self)
+    }
+
+    fn n_classes(&self) -> usize {
+        10
+    }
+
+    fn try_sample(&self, idx: usize) -> Result<(Vec<Value>, usize)> {
+        self.try_get(idx)
     }
 }
 
@@ -68,7 +346,7 @@ mod tests {
     #[test]
     fn make_moon_test() {
         let n: usize = 100;
-        let [out_circ, in_circ] = make_moon(2 * n);
+        let [out_circ, in_circ] = make_moon(2 * n).unwrap();
 
         assert_eq!(out_circ.shape(), [2, 100]);
         assert_eq!(in_circ.shape(), [2, 100]);
@@ -86,6 +364,96 @@ mod tests {
         assert_eq!(data.1, 1.);
     }
 
+    #[test]
+    fn make_moon_dataset_errors() {
+        assert!(MakeMoonDataset::try_new(0).is_err());
+        let dataset = MakeMoonDataset::try_new(10).unwrap();
+        assert!(dataset.try_get(9).is_ok());
+        assert_eq!(
+            dataset.try_get(10).unwrap_err(),
+            Error::IndexOutOfRange { index: 10, len: 10 }
+        );
+    }
+
+    #[test]
+    fn label_formats() {
+        let moons = MakeMoonDataset::new(10);
+        let (_, label) = moons.get_as(0, LabelFormat::Sign);
+        assert_eq!(label, Label::Sign(moons.get(0).1));
+        assert_eq!(moons.get_as(0, LabelFormat::Index).1, Label::Index(1));
+        assert_eq!(moons.get_as(9, LabelFormat::Index).1, Label::Index(0));
+        assert_eq!(
+            moons.get_as(9, LabelFormat::OneHot).1,
+            Label::OneHot(vec![1.0, 0.0])
+        );
+
+        let blobs = PointsDataset::blobs(30, 3, 0.1, 0).unwrap();
+        assert_eq!(blobs.len(), 30);
+        assert_eq!(blobs.n_classes(), 3);
+        assert_eq!(
+            blobs.get_as(29, LabelFormat::OneHot).1,
+            Label::OneHot(vec![0.0, 0.0, 1.0])
+        );
+        assert!(blobs.try_get_as(0, LabelFormat::Sign).is_err());
+        assert!(blobs.try_sample(30).is_err());
+        // the 2 leftover samples go to the first classes
+        let uneven = PointsDataset::blobs(11, 3, 0.1, 0).unwrap();
+        assert_eq!(uneven.len(), 11);
+        let counts = (0..3)
+            .map(|c| uneven.label.iter().filter(|&&l| l == c).count())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![4, 4, 3]);
+        // class 0 is centered on (2, 0)
+        let (x, _) = blobs.sample(0);
+        assert!((x[0].data() - 2.0).abs() < 1.0 && x[1].data().abs() < 1.0);
+    }
+
+    #[test]
+    fn spirals_dataset() {
+        let spirals = PointsDataset::spirals(300, 3, 1).unwrap();
+        let again = PointsDataset::spirals(300, 3, 1).unwrap();
+        assert_eq!(spirals.len(), 300);
+        assert_eq!(spirals.label[150], 1);
+        for i in 0..spirals.len() {
+            let (x, _) = spirals.sample(i);
+            let (y, _) = again.sample(i);
+            assert_eq!(x[0].data(), y[0].data());
+            assert!(x[0].data().hypot(x[1].data()) <= 1.0 + 1e-12);
+        }
+        assert!(PointsDataset::spirals(2, 3, 0).is_err());
+    }
+
+    #[test]
+    fn digits_dataset_load() {
+        let path = std::env::temp_dir().join("micrograd_digits_test.csv");
+        let image = (0..64).map(|i| (i % 17).to_string()).collect::<Vec<_>>();
+        let text = format!("{},3\n\n{},7\n", image.join(","), image.join(","));
+        std::fs::write(&path, text).unwrap();
+        let digits = DigitsDataset::load(&path).unwrap();
+        assert_eq!(digits.len(), 2);
+        let (x, label) = digits.get(1);
+        assert_eq!(x.len(), 64);
+        assert_eq!(label, 7);
+        assert_eq!(x[16].data(), 16.0 / 16.0);
+        assert!(digits.try_get(2).is_err());
+
+        std::fs::write(&path, "1,2,3\n").unwrap();
+        assert!(DigitsDataset::load(&path).is_err());
+        for bad in ["10", "-1", "2.5"] {
+            std::fs::write(
+                &path,
+                format!("{},3\n{},{}\n", image.join(","), image.join(","), bad),
+            )
+            .unwrap();
+            match DigitsDataset::load(&path) {
+                Err(Error::InvalidArgument(msg)) => assert!(msg.starts_with("line 2:"), "{}", msg),
+                other => panic!("unexpected result {:?}", other.map(|d| d.len())),
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(DigitsDataset::load(&path), Err(Error::Io(_))));
+    }
+
     #[test]
     fn moon_plot() {
         let n: usize = 100;
@@ -135,7 +503,7 @@ mod tests {
             .draw_series(
                 out_circ_points
                     .iter()
-                    .map(|&point| TriangleMarker::new(point, 5, &RED)),
+                    .map(|&point| TriangleMarker::new(point, 5, RED)),
             )
             .unwrap();
 
@@ -143,7 +511,7 @@ mod tests {
             .draw_series(
                 in_circ_points
                     .iter()
-                    .map(|&point| TriangleMarker::new(point, 5, &BLUE)),
+                    .map(|&point| TriangleMarker::new(point, 5, BLUE)),
             )
             .unwrap();
     }
@@ -192,7 +560,6 @@ mod tests {
                 let y = yy[(i, j)];
 
                 // 模拟分类器预测函数（替换为实际模型逻辑）
-                let score = x; // 示例逻辑：圆形边界
                 if (y - x * x).abs() < 0.01 {
                     z.push((x, y));
                     continue;
@@ -202,7 +569,7 @@ mod tests {
 
         // 绘制等高线
         // 绘制决策边界区域
-        let _ = chart.draw_series(LineSeries::new(z.iter().map(|&point| point), &CYAN));
+        let _ = chart.draw_series(LineSeries::new(z.iter().copied(), &CYAN));
 
         // 绘制散点图
         let out_circ_points: Vec<(f64, f64)> = (0..dataset.len())
@@ -225,7 +592,7 @@ mod tests {
             .draw_series(
                 out_circ_points
                     .iter()
-                    .map(|&point| TriangleMarker::new(point, 5, &RED)),
+                    .map(|&point| TriangleMarker::new(point, 5, RED)),
             )
             .unwrap();
 
@@ -233,7 +600,7 @@ mod tests {
             .draw_series(
                 in_circ_points
                     .iter()
-                    .map(|&point| TriangleMarker::new(point, 5, &BLUE)),
+                    .map(|&point| TriangleMarker::new(point, 5, BLUE)),
             )
             .unwrap();
 
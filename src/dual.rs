@@ -0,0 +1,206 @@
+//! Forward-mode automatic differentiation with dual numbers.
+//!
+//! A `Dual` carries a value and its tangent (derivative along one input direction), so one
+//! evaluation of `f` gives `J v` for a direction `v`, whatever the number of outputs. This is
+//! cheaper than `Value::backward` when a function has few inputs and many outputs.
+//! The op set and the operator impls mirror `Value`, so the same expression works on both.
+
+use std::ops::{Add, Div, Mul, Sub};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Dual {
+    pub data: f64,
+    pub tangent: f64,
+}
+
+impl Dual {
+    pub fn new(data: f64, tangent: f64) -> Dual {
+        Dual { data, tangent }
+    }
+
+    pub fn constant(data: f64) -> Dual {
+        Dual::new(data, 0.0)
+    }
+
+    pub fn data(&self) -> f64 {
+        self.data
+    }
+
+    pub fn tangent(&self) -> f64 {
+        self.tangent
+    }
+
+    pub fn relu(&self) -> Dual {
+        if self.data > 0.0 {
+            *self
+        } else {
+            Dual::constant(0.0)
+        }
+    }
+
+    pub fn pow_i(&self, rhs: &Dual) -> Dual {
+        let data = self.data.powf(rhs.data);
+        let mut tangent = rhs.data * self.data.powf(rhs.data - 1.0) * self.tangent;
+        if rhs.tangent != 0.0 {
+            tangent += data * self.data.ln() * rhs.tangent;
+        }
+        Dual::new(data, tangent)
+    }
+
+    pub fn pow<T: Into<f64>>(&self, rhs: T) -> Dual {
+        self.pow_i(&Dual::constant(rhs.into()))
+    }
+
+    pub fn exp(&self) -> Dual {
+        let data = self.data.exp();
+        Dual::new(data, data * self.tangent)
+    }
+
+    pub fn ln(&self) -> Dual {
+        Dual::new(self.data.ln(), self.tangent / self.data)
+    }
+
+    pub fn tanh(&self) -> Dual {
+        let data = self.data.tanh();
+        Dual::new(data, (1.0 - data * data) * self.tangent)
+    }
+
+    pub fn sigmoid(&self) -> Dual {
+        let data = crate::math_cal::sigmoid(self.data);
+        Dual::new(data, data * (1.0 - data) * self.tangent)
+    }
+}
+
+impl From<f64> for Dual {
+    fn from(data: f64) -> Self {
+        Dual::constant(data)
+    }
+}
+
+macro_rules! impl_dual_op {
+    ($trait:ident, $method:ident, |$a:ident, $b:ident| $body:expr) => {
+        impl $trait for &Dual {
+            type Output = Dual;
+            fn $method(self, rhs: &Dual) -> Dual {
+                let ($a, $b) = (self, rhs);
+                $body
+            }
+        }
+
+        impl $trait for Dual {
+            type Output = Dual;
+            fn $method(self, rhs: Dual) -> Dual {
+                (&self).$method(&rhs)
+            }
+        }
+
+        impl<T: Into<f64>> $trait<T> for &Dual {
+            type Output = Dual;
+            fn $method(self, rhs: T) -> Dual {
+                self.$method(&Dual::constant(rhs.into()))
+            }
+        }
+    };
+}
+
+impl_dual_op!(Add, add, |a, b| Dual::new(
+    a.data + b.data,
+    a.tangent + b.tangent
+));
+impl_dual_op!(Sub, sub, |a, b| Dual::new(
+    a.data - b.data,
+    a.tangent - b.tangent
+));
+impl_dual_op!(Mul, mul, |a, b| Dual::new(
+    a.data * b.data,
+    a.tangent * b.data + a.data * b.tangent
+));
+impl_dual_op!(Div, div, |a, b| Dual::new(
+    a.data / b.data,
+    (a.tangent * b.data - a.data * b.tangent) / (b.data * b.data)
+));
+
+/// Jacobian-vector product of `f` at `x` along `v`.
+///
+/// Returns the outputs of `f` and `J v`, both of length `f(x).len()`.
+pub fn jvp<F>(f: F, x: &[f64], v: &[f64]) -> (Vec<f64>, Vec<f64>)
+where
+    F: Fn(&[Dual]) -> Vec<Dual>,
+{
+    assert_eq!(x.len(), v.len(), "x and v must have the same length");
+    let inputs = x
+        .iter()
+        .zip(v.iter())
+        .map(|(&x, &v)| Dual::new(x, v))
+        .collect::<Vec<_>>();
+    f(&inputs)
+        .into_iter()
+        .map(|out| (out.data, out.tangent))
+        .unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+    use crate::nn::Module;
+
+    // every case is run on `Value` (reverse mode) and on `Dual` (forward mode), the gradient
+    // of the first must match the tangents of the second along each axis
+    macro_rules! cross_check {
+        ($x:expr, |$a:ident, $b:ident| $body:expr) => {{
+            let x: [f64; 2] = $x;
+
+            let ($a, $b) = (Value::new(x[0]), Value::new(x[1]));
+            let out = $body;
+            out.backward();
+            let reverse = [$a.grad(), $b.grad()];
+
+            for (i, axis) in [[1.0, 0.0], [0.0, 1.0]].iter().enumerate() {
+                let ($a, $b) = (Dual::new(x[0], axis[0]), Dual::new(x[1], axis[1]));
+                let out_dual = $body;
+                assert!((out_dual.data() - out.data()).abs() < 1e-9);
+                assert!(
+                    (out_dual.tangent() - reverse[i]).abs() < 1e-9,
+                    "d/dx{}: forward {} reverse {}",
+                    i,
+                    out_dual.tangent(),
+                    reverse[i]
+                );
+            }
+        }};
+    }
+
+    #[test]
+    // the expressions are written for `&Value`, the refs are needless only for `Dual`
+    #[allow(clippy::op_ref)]
+    fn dual_matches_reverse_mode() {
+        cross_check!([1.5, -2.0], |a, b| &(&a * &b) + &a);
+        cross_check!([1.5, -2.0], |a, b| &(&a - &b) * &(&b - 3.0));
+        cross_check!([1.5, -2.0], |a, b| &a / &b);
+        cross_check!([1.5, 2.5], |a, b| a.pow_i(&b));
+        cross_check!([1.5, -2.0], |a, b| &a.pow(3.0) * &b.exp());
+        cross_check!([1.5, 0.5], |a, b| &(&a * &b).ln() + &(&b - &a).relu());
+        cross_check!([-1.5, 0.5], |a, b| &(&a * &b).relu() / &(&b + 2.0));
+        cross_check!([-1.5, 0.5], |a, b| &a.tanh() * &(&a * &b).sigmoid());
+        cross_check!([30.0, -40.0], |a, b| &(&a + &b).sigmoid() + &b.tanh());
+    }
+
+    #[test]
+    fn jvp_of_mlp_matches_backward() {
+        let mlp = crate::MLP::new(2, &[8, 3]);
+        let x = [0.3, -0.7];
+        let v = [0.6, 0.8];
+        let (out, jv) = jvp(|x| mlp.forward_dual(x), &x, &v);
+
+        for k in 0..3 {
+            let inputs = vec![Value::new(x[0]), Value::new(x[1])];
+            let y = mlp.forward(&inputs).remove(k);
+            y.backward();
+            assert!((out[k] - y.data()).abs() < 1e-9);
+            let expected = inputs[0].grad() * v[0] + inputs[1].grad() * v[1];
+            assert!((jv[k] - expected).abs() < 1e-9);
+        }
+        mlp.zero_grad();
+    }
+}
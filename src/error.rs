@@ -0,0 +1,108 @@
+use std::fmt;
+
+/// Errors returned by the `try_*` variants of the library functions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A module got an input with the wrong number of features.
+    InputSize {
+        expected: usize,
+        got: usize,
+    },
+    /// A model output does not have the size a loss expects.
+    OutputSize {
+        expected: usize,
+        got: usize,
+    },
+    /// Loading parameters with a different count than the model has.
+    ParameterCount {
+        expected: usize,
+        got: usize,
+    },
+    IndexOutOfRange {
+        index: usize,
+        len: usize,
+    },
+    InvalidArgument(String),
+    /// An ndarray shape error while building a dataset.
+    Shape(String),
+    /// Reading a data file failed.
+    Io(String),
+    /// A NaN or infinity found in anomaly detection mode, see `anomaly::set_detect_anomaly`.
+    /// `chain` lists the ops that produced it, newest first.
+    NonFinite {
+        stage: String,
+        node: String,
+        value: f64,
+        chain: Vec<String>,
+    },
+    /// Backward through a graph whose intermediate nodes were already freed, by a previous
+    /// backward without `retain_graph` or by `Value::free_graph`.
+    GraphFreed {
+        node: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InputSize { expected, got } => {
+                write!(f, "input size mismatch: expected {}, got {}", expected, got)
+            }
+            Error::OutputSize { expected, got } => {
+                write!(
+                    f,
+                    "output size mismatch: expected {}, got {}",
+                    expected, got
+                )
+            }
+            Error::ParameterCount { expected, got } => {
+                write!(
+                    f,
+                    "parameter count mismatch: expected {}, got {}",
+                    expected, got
+                )
+            }
+            Error::IndexOutOfRange { index, len } => {
+                write!(f, "index {} out of range for length {}", index, len)
+            }
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Shape(msg) => write!(f, "shape error: {}", msg),
+            Error::Io(msg) => write!(f, "io error: {}", msg),
+            Error::NonFinite {
+                stage,
+                node,
+                value,
+                chain,
+            } => write!(
+                f,
+                "non-finite {} value {} at {}, produced by: {}",
+                stage,
+                value,
+                node,
+                chain.join(" <- ")
+            ),
+            Error::GraphFreed { node } => write!(
+                f,
+                "backward through a freed graph at {}, pass retain_graph: true to the first \
+                 backward to go through the graph more than once",
+                node
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ndarray::ShapeError> for Error {
+    fn from(err: ndarray::ShapeError) -> Self {
+        Error::Shape(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err.to_string())
+    }
+}
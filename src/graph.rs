@@ -0,0 +1,148 @@
+//! Read access to the computation graph, to see what an epoch actually builds.
+
+use crate::{Prev, Value, ValueInner};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem::size_of;
+use std::sync::{Arc, RwLock};
+
+impl Value {
+    /// The op that created this node, empty for a leaf.
+    pub fn op(&self) -> String {
+        self.0.read().unwrap()._op.clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.0.read().unwrap().name.clone()
+    }
+
+    /// Operands of the op that created this node, in order.
+    pub fn parents(&self) -> Vec<Value> {
+        self.0
+            .read()
+            .unwrap()
+            ._prev
+            .iter()
+            .map(|p| p.value())
+            .collect()
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.0.read().unwrap()._prev.is_empty()
+    }
+
+    /// Summary of the graph this node was computed from.
+    pub fn graph_stats(&self) -> GraphStats {
+        let mut topo = Vec::new();
+        self.build_topo(&mut topo, &mut HashSet::new(), false);
+
+        let mut stats = GraphStats::default();
+        // depth of a node: number of nodes on the longest path from it down to a leaf
+        let mut depth: HashMap<*const RwLock<ValueInner>, usize> = HashMap::new();
+        for node in topo.iter() {
+            let inner = node.read().unwrap();
+            let d = 1 + inner
+                ._prev
+                .iter()
+                .map(|p| depth[&Arc::as_ptr(&p.0)])
+                .max()
+                .unwrap_or(0);
+            depth.insert(Arc::as_ptr(node), d);
+
+            stats.node_count += 1;
+            stats.max_depth = stats.max_depth.max(d);
+            if inner._prev.is_empty() {
+                stats.leaf_count += 1;
+                if inner.requires_grad {
+                    stats.trainable_leaf_count += 1;
+                }
+            } else {
+                *stats.op_counts.entry(inner._op.clone()).or_insert(0) += 1;
+            }
+            stats.memory_bytes += node_size(&inner);
+        }
+        stats
+    }
+
+    /// For each of `params`, whether a gradient can flow from this node to it.
+    pub fn reachable_parameters(&self, params: &[Prev]) -> Vec<bool> {
+        let mut topo = Vec::new();
+        let mut visited = HashSet::new();
+        self.build_topo(&mut topo, &mut visited, true);
+        params
+            .iter()
+            .map(|p| visited.contains(&Arc::as_ptr(&p.0)))
+            .collect()
+    }
+}
+
+/// Counts over the graph of a node, see `Value::graph_stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Leaves that require grad, e.g. the parameters.
+    pub trainable_leaf_count: usize,
+    /// Nodes on the longest path from the root to a leaf, the root included.
+    pub max_depth: usize,
+    /// Number of nodes created by each op.
+    pub op_counts: BTreeMap<String, usize>,
+    /// Rough estimate of the heap used by the nodes, closures excluded.
+    pub memory_bytes: usize,
+}
+
+fn node_size(inner: &ValueInner) -> usize {
+    // Arc strong and weak counts + the node itself
+    2 * size_of::<usize>()
+        + size_of::<RwLock<ValueInner>>()
+        + inner.name.capacity()
+        + inner._op.capacity()
+        + inner._prev.capacity() * size_of::<Prev>()
+        + inner._hooks.capacity() * size_of::<usize>() * 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MLP;
+    use crate::nn::Module;
+
+    #[test]
+    fn graph_stats_test() {
+        let a = Value::new_with_name(2.0, "a".to_string());
+        let b = Value::new_with_name(3.0, "b".to_string());
+        let c = &(&a * &b) + &a;
+        let d = (&c * 2.0).relu();
+
+        assert_eq!(d.op(), "ReLU");
+        assert_eq!(d.parents()[0].op(), "*");
+        assert_eq!(c.parents()[1].name(), "a");
+        assert!(a.is_leaf());
+
+        let stats = d.graph_stats();
+        // a, b, a * b, c, 2.0, c * 2, relu
+        assert_eq!(stats.node_count, 7);
+        assert_eq!(stats.leaf_count, 3);
+        assert_eq!(stats.trainable_leaf_count, 2);
+        assert_eq!(stats.max_depth, 5);
+        assert_eq!(stats.op_counts["*"], 2);
+        assert_eq!(stats.op_counts["+"], 1);
+        assert!(stats.memory_bytes > 0);
+    }
+
+    #[test]
+    fn reachable_parameters_test() {
+        let mlp = MLP::new(2, &[2, 1]);
+        let params = mlp.parameters();
+        let out = &mlp.forward(&vec![Value::constant(1.0), Value::constant(1.0)])[0];
+        assert!(out.reachable_parameters(&params).iter().all(|&r| r));
+
+        // freeze the first neuron, its parameters are no longer reached
+        for p in params[..3].iter() {
+            p.value().set_requires_grad(false);
+        }
+        let out = &mlp.forward(&vec![Value::constant(1.0), Value::constant(1.0)])[0];
+        let reachable = out.reachable_parameters(&params);
+        assert_eq!(reachable[..3], [false, false, false]);
+        assert!(reachable[3..].iter().all(|&r| r));
+    }
+}
@@ -1,20 +1,40 @@
+pub mod anomaly;
+pub mod autograd;
+pub mod conv;
+mod custom_op;
 mod data;
+pub mod dual;
+mod error;
+pub mod graph;
+pub mod loss;
+pub mod makemore;
 mod math_cal;
 pub mod nn;
+pub mod optim;
+pub mod parallel;
+pub mod rnn;
+pub mod tape;
 
-use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashSet;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 
 // 重新导出结构体，使其对 crate 外部可见
-pub use data::MakeMoonDataset;
+pub use custom_op::CustomOp;
+pub use data::{
+    ClassificationDataset, DigitsDataset, Label, LabelFormat, MakeMoonDataset, PointsDataset,
+};
+pub use error::{Error, Result};
+pub use math_cal::{log_softmax, softmax};
 
 pub use nn::MLP;
 
-#[derive()]
-pub struct Value(Rc<RefCell<ValueInner>>);
+/// A node of the computation graph.
+///
+/// Nodes are shared through `Arc<RwLock<_>>`, so a `Value` is `Send + Sync` and the same
+/// parameters can be used to build graphs on several threads at once.
+pub struct Value(Arc<RwLock<ValueInner>>);
 
 impl Value {
     pub(crate) fn clone(&self) -> Value {
@@ -25,71 +45,212 @@ impl Value {
 
 impl Value {
     pub fn new(data: f64) -> Self {
-        Value(Rc::new(RefCell::new(ValueInner::new(data))))
+        Value(Arc::new(RwLock::new(ValueInner::new(data))))
     }
 
     pub fn new_with_name(data: f64, name: String) -> Self {
-        Value(Rc::new(RefCell::new(ValueInner::new_with_name(data, name))))
+        Value(Arc::new(RwLock::new(ValueInner::new_with_name(data, name))))
     }
+    /// A leaf that never gets a gradient, for inputs, labels and hyper-parameters.
+    pub fn constant(data: f64) -> Self {
+        let mut inner = ValueInner::new(data);
+        inner.requires_grad = false;
+        Value(Arc::new(RwLock::new(inner)))
+    }
+
+    /// A constant copy of this node: same data, no history and no gradient.
+    pub fn detach(&self) -> Value {
+        let inner = self.0.read().unwrap();
+        let mut detached = ValueInner::new_with_name(inner.data, inner.name.clone());
+        detached.requires_grad = false;
+        Value(Arc::new(RwLock::new(detached)))
+    }
+
+    /// Registers a closure called during `backward` with every gradient contribution this node
+    /// receives, before it is added to its grad. The returned value is added instead, so hooks
+    /// can clip, log or perturb gradients before they flow to the parents.
+    ///
+    /// Hooks run while the node is locked for the accumulation, which keeps them consistent
+    /// when several threads propagate into the same parameter. They must not access the node.
+    pub fn register_hook<F>(&self, hook: F)
+    where
+        F: Fn(f64) -> f64 + Send + Sync + 'static,
+    {
+        self.0.write().unwrap()._hooks.push(Arc::new(hook));
+    }
+
+    pub fn clear_hooks(&self) {
+        self.0.write().unwrap()._hooks.clear();
+    }
+
+    pub fn requires_grad(&self) -> bool {
+        self.0.read().unwrap().requires_grad
+    }
+
+    /// Only meant for leaves, e.g. to freeze a parameter. Results of ops take it from their
+    /// operands when they are created.
+    pub fn set_requires_grad(&self, requires_grad: bool) {
+        self.0.write().unwrap().requires_grad = requires_grad;
+    }
+
     pub fn data(&self) -> f64 {
-        self.0.borrow().data
+        self.0.read().unwrap().data
     }
 
     pub fn set_data(&self, data: f64) {
-        self.0.borrow_mut().data = data;
+        self.0.write().unwrap().data = data;
     }
 
     pub fn add_data(&self, add_data: f64) {
-        self.0.borrow_mut().data += add_data;
+        self.0.write().unwrap().data += add_data;
     }
 
     pub fn grad(&self) -> f64 {
-        self.0.borrow().grad
+        self.0.read().unwrap().grad
     }
     pub fn set_grad(&self, grad: f64) {
-        self.0.borrow_mut().grad = grad;
+        self.0.write().unwrap().grad = grad;
     }
 
     pub fn backward(&self) {
+        self.backward_with_options(BackwardOptions::default());
+    }
+
+    /// Backward pass seeded with `seed_grad` instead of 1.0, e.g. the upstream gradient when
+    /// `self` is an intermediate result.
+    pub fn backward_with(&self, seed_grad: f64) {
+        self.backward_with_options(BackwardOptions {
+            seed_grad,
+            ..Default::default()
+        });
+    }
+
+    pub fn backward_with_options(&self, options: BackwardOptions) {
+        if let Err(e) = self.try_backward_with_options(options) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Like `backward`, but in anomaly detection mode the first non-finite gradient is
+    /// returned as an error instead of panicking. See `anomaly::set_detect_anomaly`.
+    pub fn try_backward(&self) -> Result<()> {
+        self.try_backward_with_options(BackwardOptions::default())
+    }
+
+    pub fn try_backward_with_options(&self, options: BackwardOptions) -> Result<()> {
+        let check = anomaly::is_anomaly_detection_enabled();
         // 构建计算图拓扑排序
         let mut topo = Vec::new();
         let mut visited = HashSet::new();
-        self.build_topo(&mut topo, &mut visited);
+        self.build_topo(&mut topo, &mut visited, true);
+        check_not_freed(&topo)?;
+
+        if options.zero_grad {
+            for node in topo.iter() {
+                let mut node = node.write().unwrap();
+                if !node._prev.is_empty() {
+                    node.grad = 0.0;
+                }
+            }
+        }
 
         // # go one variable at a time and apply the chain rule to get its gradient
-        self.set_grad(1.0);
+        {
+            let mut root = self.0.write().unwrap();
+            root.grad = root.run_hooks(options.seed_grad);
+        }
         topo.reverse();
 
         for node in topo.iter() {
-            // println!("{:?}", &node.borrow());
-            if let Some(backward_fn) = &node.borrow()._backward {
+            // println!("{:?}", &node.read().unwrap());
+            if check {
+                anomaly::check_backward(node, node)?;
+            }
+            // clone the closure out so the node is not locked while it runs
+            let backward_fn = node.read().unwrap()._backward.clone();
+            if let Some(backward_fn) = backward_fn {
                 backward_fn();
+                if check {
+                    let prev = node
+                        .read()
+                        .unwrap()
+                        ._prev
+                        .iter()
+                        .map(|p| p.0.clone())
+                        .collect::<Vec<_>>();
+                    for p in prev.iter() {
+                        anomaly::check_backward(p, node)?;
+                    }
+                }
+            }
+        }
+
+        if !options.retain_graph {
+            for node in topo.iter() {
+                let mut node = node.write().unwrap();
+                node._freed |= node._backward.is_some() || !node._prev.is_empty();
+                node._backward = None;
+                node._grad_fn = None;
+                node._prev.clear();
             }
         }
+        Ok(())
+    }
+
+    /// Detaches this node from the graph it was computed from: it keeps its data and grad but
+    /// becomes a leaf, and the intermediate nodes nothing else refers to are freed.
+    /// `backward` already does it unless `retain_graph` is set. A backward through the node
+    /// afterwards is an error.
+    pub fn free_graph(&self) {
+        let prev = {
+            let mut inner = self.0.write().unwrap();
+            inner._freed |= inner._backward.is_some() || !inner._prev.is_empty();
+            inner._backward = None;
+            inner._grad_fn = None;
+            std::mem::take(&mut inner._prev)
+        };
+        // dropped here without the lock, through the iterative drop of ValueInner
+        drop(prev);
     }
 
+    /// Post-order DFS, done with an explicit stack so the depth of the graph is not bounded by
+    /// the thread stack size. With `only_requires_grad` subgraphs that do not require grad are
+    /// skipped.
     fn build_topo(
         &self,
-        topo: &mut Vec<Rc<RefCell<ValueInner>>>,
-        visited: &mut HashSet<*const RefCell<ValueInner>>,
+        topo: &mut Vec<Arc<RwLock<ValueInner>>>,
+        visited: &mut HashSet<*const RwLock<ValueInner>>,
+        only_requires_grad: bool,
     ) {
-        let ptr = Rc::as_ptr(&self.0) as *const RefCell<ValueInner>;
-        if visited.contains(&ptr) {
-            return;
-        }
-        visited.insert(ptr);
-
-        for prev in self.0.borrow()._prev.iter() {
-            // if let rc = prev.0 {
-            Value(prev.0.clone()).build_topo(topo, visited);
-            // }
+        // (node, children already pushed)
+        let mut stack = vec![(self.0.clone(), false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                topo.push(node);
+                continue;
+            }
+            if !visited.insert(Arc::as_ptr(&node)) {
+                continue;
+            }
+            let children = node
+                .read()
+                .unwrap()
+                ._prev
+                .iter()
+                .filter(|prev| {
+                    !visited.contains(&Arc::as_ptr(&prev.0))
+                        && (!only_requires_grad || prev.0.read().unwrap().requires_grad)
+                })
+                .map(|prev| (prev.0.clone(), false))
+                .collect::<Vec<_>>();
+            stack.push((node, true));
+            stack.extend(children);
         }
-        topo.push(self.0.clone());
     }
 }
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let inner = self.0.borrow();
+        let inner = self.0.read().unwrap();
         f.debug_struct("Value")
             .field("data", &inner.data)
             .field("grad", &inner.grad)
@@ -99,37 +260,119 @@ impl fmt::Debug for Value {
     }
 }
 
+/// Error for the first node of `topo` whose graph was freed.
+fn check_not_freed(topo: &[Arc<RwLock<ValueInner>>]) -> Result<()> {
+    match topo.iter().find(|node| node.read().unwrap()._freed) {
+        Some(node) => Err(Error::GraphFreed {
+            node: anomaly::label(&node.read().unwrap()),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Options of `Value::backward_with_options`, the default is what `Value::backward` does.
+#[derive(Debug, Clone, Copy)]
+pub struct BackwardOptions {
+    /// Gradient written into the root before the pass.
+    pub seed_grad: f64,
+    /// Reset the grad of every non-leaf node of the graph before the pass, so calling backward
+    /// again on the same graph does not add into the grads of the previous pass.
+    /// Leaves (parameters) keep accumulating, use `Module::zero_grad` for them.
+    pub zero_grad: bool,
+    /// Keep the graph after the pass, off by default: every non-leaf node then drops its
+    /// backward closure and its parents, so the intermediate nodes can be freed. Set it to
+    /// call backward again on the same graph or to differentiate it with `autograd::grad`.
+    pub retain_graph: bool,
+}
+
+impl Default for BackwardOptions {
+    fn default() -> Self {
+        BackwardOptions {
+            seed_grad: 1.0,
+            zero_grad: false,
+            retain_graph: false,
+        }
+    }
+}
+
+/// Adds the gradient of a node into the grads of its parents.
+type BackwardFn = Arc<dyn Fn() + Send + Sync>;
+/// Gradient of an op built as a graph: (parents, upstream grad) -> one grad per parent.
+/// Used by `autograd::grad` so the gradients can be differentiated again.
+type GradFn = Arc<dyn Fn(&[Value], &Value) -> Vec<Value> + Send + Sync>;
+/// See `Value::register_hook`.
+type GradHook = Arc<dyn Fn(f64) -> f64 + Send + Sync>;
+
 struct ValueInner {
     name: String,
     data: f64,
     grad: f64,
-    _backward: Option<Box<dyn Fn()>>,
-    _prev: HashSet<Prev>,
+    requires_grad: bool,
+    _backward: Option<BackwardFn>,
+    _grad_fn: Option<GradFn>,
+    _hooks: Vec<GradHook>,
+    // set when the backward closure and the parents of a result were dropped
+    _freed: bool,
+    // operands in order, e.g. [base, exponent] for pow
+    _prev: Vec<Prev>,
     _op: String,
 }
 
 impl ValueInner {
     pub fn new(val: f64) -> ValueInner {
-        let inner = ValueInner {
+        ValueInner {
             data: val,
             grad: 0.0,
+            requires_grad: true,
             _backward: None,
+            _grad_fn: None,
+            _hooks: Vec::new(),
+            _freed: false,
             _prev: Default::default(),
             _op: Default::default(),
             name: Default::default(),
-        };
-        inner
+        }
     }
     pub fn new_with_name(val: f64, name: String) -> ValueInner {
-        let inner = ValueInner {
+        ValueInner {
             data: val,
             grad: 0.0,
+            requires_grad: true,
             _backward: None,
+            _grad_fn: None,
+            _hooks: Vec::new(),
+            _freed: false,
             _prev: Default::default(),
             _op: Default::default(),
             name,
-        };
-        inner
+        }
+    }
+}
+
+impl ValueInner {
+    fn add_grad(&mut self, grad: f64) {
+        if self.requires_grad {
+            self.grad += self.run_hooks(grad);
+        }
+    }
+
+    /// Passes a gradient contribution through the hooks, in registration order.
+    fn run_hooks(&self, grad: f64) -> f64 {
+        self._hooks.iter().fold(grad, |g, hook| hook(g))
+    }
+}
+
+impl Drop for ValueInner {
+    /// Frees the parents that are only referenced by this node with an explicit stack, the
+    /// default recursive drop overflows the thread stack on long chains.
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self._prev);
+        while let Some(prev) = stack.pop() {
+            if let Some(lock) = Arc::into_inner(prev.0) {
+                let mut inner = lock.into_inner().unwrap_or_else(|e| e.into_inner());
+                stack.append(&mut inner._prev);
+            }
+        }
     }
 }
 
@@ -144,8 +387,9 @@ impl fmt::Debug for ValueInner {
             .finish()
     }
 }
-// use RC rather than weak , because we need to keep the ValueInner alive,or the ValueInner will be dropped automatically.
-pub struct Prev(Rc<RefCell<ValueInner>>);
+// use Arc rather than weak , because we need to keep the ValueInner alive,or the ValueInner will be dropped automatically.
+#[derive(Clone)]
+pub struct Prev(Arc<RwLock<ValueInner>>);
 
 impl Prev {
     pub fn value(&self) -> Value {
@@ -156,15 +400,186 @@ impl Prev {
 impl Hash for Prev {
     fn hash<H: Hasher>(&self, state: &mut H) {
         // Hash based on the address of the control block
-        self.0.as_ptr().hash(state);
+        Arc::as_ptr(&self.0).hash(state);
     }
 }
 
 impl PartialEq for Prev {
     fn eq(&self, other: &Self) -> bool {
         // Safely compares Weak pointers without unwrapping
-        Rc::ptr_eq(&self.0, &other.0)
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
 impl Eq for Prev {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backward_on_deep_chain() {
+        let x = Value::new_with_name(1.0, "x".to_string());
+        let mut y = x.clone();
+        for _ in 0..1_000_000 {
+            y = y.relu();
+        }
+        y.backward();
+        assert_eq!(x.grad(), 1.0);
+    }
+
+    #[test]
+    fn free_graph_releases_intermediate_nodes() {
+        let a = Value::new(3.0);
+        let b = &a * 2.0;
+        let c = (&b + &a).relu();
+        let b_weak = Arc::downgrade(&b.0);
+        drop(b);
+
+        c.backward_with_options(BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        });
+        assert_eq!(Arc::strong_count(&a.0), 3);
+        c.free_graph();
+        assert!(b_weak.upgrade().is_none());
+        assert_eq!(Arc::strong_count(&a.0), 1);
+        assert!(c.is_leaf());
+        assert_eq!(c.data(), 9.0);
+        assert_eq!(a.grad(), 3.0);
+        assert!(c.try_backward().is_err());
+    }
+
+    #[test]
+    fn backward_with_seed_and_zero_grad() {
+        let a = Value::new(3.0);
+        let b = &a * &a;
+        let c = &b * 2.0;
+        let retain = BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        };
+
+        c.backward_with_options(BackwardOptions {
+            seed_grad: 0.5,
+            ..retain
+        });
+        assert_eq!(b.grad(), 1.0);
+        assert_eq!(a.grad(), 6.0);
+
+        // without zero_grad the intermediate grad is counted twice
+        c.backward_with_options(retain);
+        assert_eq!(b.grad(), 3.0);
+
+        a.set_grad(0.0);
+        c.backward_with_options(BackwardOptions {
+            zero_grad: true,
+            ..retain
+        });
+        assert_eq!(b.grad(), 2.0);
+        assert_eq!(a.grad(), 12.0);
+    }
+
+    #[test]
+    fn backward_frees_graph_unless_retained() {
+        let a = Value::new(3.0);
+        let b = &a * &a;
+        let b_weak = Arc::downgrade(&b.0);
+        let c = &b + 1.0;
+        drop(b);
+
+        c.backward_with_options(BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        });
+        assert_eq!(a.grad(), 6.0);
+        assert!(b_weak.upgrade().is_some());
+
+        // the default pass frees the graph; b still holds the grad of the first pass
+        c.backward();
+        assert_eq!(a.grad(), 6.0 + 12.0);
+        assert!(c.0.read().unwrap()._prev.is_empty());
+        assert!(b_weak.upgrade().is_none());
+
+        // going through a freed graph again is an error, not a silent no-op
+        assert_eq!(
+            c.try_backward(),
+            Err(Error::GraphFreed {
+                node: "+".to_string()
+            })
+        );
+        let d = &c * 2.0;
+        assert!(matches!(d.try_backward(), Err(Error::GraphFreed { .. })));
+        let err = std::panic::catch_unwind(|| autograd::grad(&c, &[&a], false));
+        assert!(err.is_err());
+        assert_eq!(a.grad(), 18.0);
+
+        // leaves stay usable in new graphs
+        (&a * 2.0).backward();
+        assert_eq!(a.grad(), 20.0);
+    }
+
+    #[test]
+    fn constants_and_detached_values_get_no_grad() {
+        let w = Value::new(2.0);
+        let x = Value::constant(3.0);
+        let y = &(&w * &x) + 1.0;
+        let frozen = Value::new(5.0);
+        frozen.set_requires_grad(false);
+        let z = &(&y * &y.detach()) + &(&frozen * &x);
+        assert!(!(&frozen * &x).requires_grad());
+
+        z.backward();
+        // z = y * const(y) + ..., dz/dw = x * y
+        assert_eq!(w.grad(), 3.0 * 7.0);
+        assert_eq!(x.grad(), 0.0);
+        assert_eq!(frozen.grad(), 0.0);
+    }
+
+    #[test]
+    fn hooks_modify_incoming_grad() {
+        let a = Value::new(2.0);
+        let b = &a * 3.0;
+        let c = &b * &b;
+        // clip the grad flowing out of b
+        b.register_hook(|g| g.clamp(-1.0, 1.0));
+        let seen = Arc::new(RwLock::new(Vec::new()));
+        let log = seen.clone();
+        a.register_hook(move |g| {
+            log.write().unwrap().push(g);
+            g
+        });
+
+        let retain = BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        };
+        c.backward_with_options(retain);
+        // b * b sends b two contributions of 6, each clipped on its own
+        assert_eq!(b.grad(), 2.0);
+        assert_eq!(a.grad(), 6.0);
+        // the leaf hook only sees what the second pass adds
+        let zero_grad = BackwardOptions {
+            zero_grad: true,
+            ..retain
+        };
+        c.backward_with_options(zero_grad);
+        assert_eq!(a.grad(), 12.0);
+        assert_eq!(*seen.read().unwrap(), vec![6.0, 6.0]);
+
+        a.clear_hooks();
+        b.clear_hooks();
+        c.backward_with_options(zero_grad);
+        assert_eq!(a.grad(), 12.0 + 36.0);
+    }
+
+    #[test]
+    fn backward_visits_shared_nodes_once() {
+        let a = Value::new(2.0);
+        let b = &a * &a;
+        let c = &(&b + &a) * &b;
+        c.backward();
+        // c = a^4 + a^3
+        assert_eq!(a.grad(), 4.0 * 8.0 + 3.0 * 4.0);
+    }
+}
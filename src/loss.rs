@@ -0,0 +1,71 @@
+//! Loss functions over the outputs of a model.
+
+use crate::error::{Error, Result};
+use crate::{Value, log_softmax};
+
+/// Softmax cross-entropy of the unnormalized `logits` for the class `target`,
+/// `-ln(softmax(logits)[target])`.
+pub fn cross_entropy(logits: &[Value], target: usize) -> Value {
+    try_cross_entropy(logits, target).unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn try_cross_entropy(logits: &[Value], target: usize) -> Result<Value> {
+    if target >= logits.len() {
+        return Err(Error::IndexOutOfRange {
+            index: target,
+            len: logits.len(),
+        });
+    }
+    Ok(&log_softmax(logits)[target] * -1.0)
+}
+
+/// Mean of `losses`, e.g. the per-sample losses of a batch.
+pub fn mean(losses: &[Value]) -> Value {
+    let mut sum = Value::constant(0.0);
+    for l in losses.iter() {
+        sum = &sum + l;
+    }
+    &sum / losses.len().max(1) as f64
+}
+
+/// Index of the largest output, the predicted class of a classifier.
+pub fn argmax(outputs: &[Value]) -> usize {
+    outputs
+        .iter()
+        .enumerate()
+        .fold((0, f64::NEG_INFINITY), |(best, max), (i, o)| {
+            if o.data() > max {
+                (i, o.data())
+            } else {
+                (best, max)
+            }
+        })
+        .0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cross_entropy_value_and_grad() {
+        let logits = [1.0, 2.0, 0.5].map(Value::new);
+        let loss = cross_entropy(&logits, 1);
+        let exps = [1.0f64, 2.0, 0.5].map(f64::exp);
+        let sum = exps.iter().sum::<f64>();
+        assert!((loss.data() + (exps[1] / sum).ln()).abs() < 1e-12);
+
+        // d loss / d logit_i = softmax_i - [i == target]
+        loss.backward();
+        for (i, l) in logits.iter().enumerate() {
+            let expected = exps[i] / sum - if i == 1 { 1.0 } else { 0.0 };
+            assert!((l.grad() - expected).abs() < 1e-12);
+        }
+
+        // large logits do not overflow
+        let big = [1000.0, 0.0].map(Value::new);
+        assert!(cross_entropy(&big, 0).data().abs() < 1e-12);
+        assert!(try_cross_entropy(&big, 2).is_err());
+        assert_eq!(argmax(&logits), 1);
+    }
+}
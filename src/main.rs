@@ -1,17 +1,73 @@
+use micrograd_rs::loss::{argmax, cross_entropy, mean};
 use micrograd_rs::nn::Module;
-use micrograd_rs::{MLP, MakeMoonDataset, Value};
-use ndarray::{Array, Array2};
+use micrograd_rs::{
+    ClassificationDataset, Error, Label, LabelFormat, MLP, MakeMoonDataset, PointsDataset, Result,
+    Value,
+};
+use ndarray::Array;
 use std::ops::{Div, Mul};
 
-fn main() {
-    demo_mlp();
+// cargo run -- [moons|blobs|spirals], the default is the ±1 max-margin demo on moons
+fn main() -> Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        Some("moons") => demo_multiclass(&MakeMoonDataset::try_new(200)?),
+        Some("blobs") => demo_multiclass(&PointsDataset::blobs(300, 4, 0.5, 0)?),
+        Some("spirals") => demo_multiclass(&PointsDataset::spirals(300, 3, 0)?),
+        Some(other) => Err(Error::InvalidArgument(format!("unknown demo {}", other))),
+        None => demo_mlp(),
+    }
+}
+
+/// K-class training with softmax cross-entropy on class indices, accuracy by argmax.
+fn demo_multiclass<D: ClassificationDataset>(dataset: &D) -> Result<()> {
+    let n_classes = dataset.n_classes();
+    let mlp = MLP::new(2, &[16, 16, n_classes]);
+
+    let total_epoch = 200;
+    for epoch in 0..total_epoch {
+        let mut correct = 0;
+        let mut losses = Vec::with_capacity(dataset.len());
+        for idx in 0..dataset.len() {
+            let (x, label) = dataset.try_get_as(idx, LabelFormat::Index)?;
+            let Label::Index(class) = label else {
+                unreachable!("index labels were requested");
+            };
+            let output = mlp.try_forward(&x)?;
+            if argmax(&output) == class {
+                correct += 1;
+            }
+            losses.push(cross_entropy(&output, class));
+        }
+        let acc = correct as f64 / dataset.len() as f64;
+        let mut loss = mean(&losses);
+        let alpha = 0.0001;
+        for p in mlp.parameters().iter() {
+            loss = &loss + &(&Value::constant(alpha) * &(&p.value() * &p.value()))
+        }
+
+        mlp.zero_grad();
+        loss.backward();
+        let learning_rate = (1.0 - 0.9 * (epoch as f64) / total_epoch as f64) * 0.5;
+        for p in mlp.parameters().iter() {
+            p.value().add_data(-learning_rate * p.value().grad());
+        }
+
+        if epoch % 10 == 0 {
+            println!("epoch: {}, loss: {}, acc: {}", epoch, loss.data(), acc);
+        }
+        if acc > 0.95 {
+            println!("epoch: {}, loss: {}, acc: {}", epoch, loss.data(), acc);
+            break;
+        }
+    }
+    Ok(())
 }
 
-fn demo_mlp() {
+fn demo_mlp() -> Result<()> {
     let mlp = MLP::new(2, &[16, 16, 1]); // 2 ->16 ->16 ->1
 
     let n: usize = 500;
-    let dataset = MakeMoonDataset::new(n);
+    let dataset = MakeMoonDataset::try_new(n)?;
 
     let total_epoch = 50;
     for epoch in 0..total_epoch {
@@ -25,12 +81,12 @@ fn demo_mlp() {
                 panic!("label must be -1.0 or 1.0,lable {}", label);
             }
 
-            let output = mlp.forward(&data);
+            let output = mlp.try_forward(&data)?;
             // if idx % 100 == 0 {
             //     println!("{}", output[0].data())
             // }
 
-            let current_loss = cal_loss(&output, &Value::new(label));
+            let current_loss = cal_loss(&output, &Value::constant(label))?;
 
             loss = &loss + &current_loss;
             if (output[0].data() > 0.0 && label == 1.0) || (output[0].data() < 0.0 && label == -1.0)
@@ -39,11 +95,11 @@ fn demo_mlp() {
             }
         }
         let acc = correct / dataset.len() as f64;
-        loss = &loss / &(Value::new(dataset.len() as f64));
+        loss = &loss / &(Value::constant(dataset.len() as f64));
         let alpha = 0.0001;
 
         for p in mlp.parameters().iter() {
-            loss = &loss + &(&Value::new(alpha) * &(&p.value() * &p.value()))
+            loss = &loss + &(&Value::constant(alpha) * &(&p.value() * &p.value()))
         }
 
         mlp.zero_grad();
@@ -51,27 +107,26 @@ fn demo_mlp() {
         // # update (sgd)
         let learning_rate = (1.0 - 0.9 * (epoch as f64) / total_epoch as f64) * 0.05;
         for p in mlp.parameters().iter() {
-            p.value().add_data(-1.0 * learning_rate * p.value().grad());
+            p.value().add_data(-learning_rate * p.value().grad());
         }
 
-        if epoch % 1 == 0 {
-            println!(
-                "epoch: {}, loss: {}, acc: {}, mlp.parameters[10].value:{},mlp.parameters[10].grad:{},mlp.parameters.len:{}",
-                epoch,
-                loss.data(),
-                acc,
-                // learning_rate, learning_rate:{},
-                mlp.parameters()[20].value().data(),
-                mlp.parameters()[20].value().grad(),
-                mlp.parameters().len(),
-            );
-        }
+        println!(
+            "epoch: {}, loss: {}, acc: {}, mlp.parameters[10].value:{},mlp.parameters[10].grad:{},mlp.parameters.len:{}",
+            epoch,
+            loss.data(),
+            acc,
+            // learning_rate, learning_rate:{},
+            mlp.parameters()[20].value().data(),
+            mlp.parameters()[20].value().grad(),
+            mlp.parameters().len(),
+        );
         if acc > 0.9 {
             break;
         }
     }
 
-    plot_pred_result("moon_dataset_pred.png", &dataset, &mlp)
+    plot_pred_result("moon_dataset_pred.png", &dataset, &mlp);
+    Ok(())
 }
 
 fn plot_pred_result(pic_name: &str, dataset: &MakeMoonDataset, mlp: &MLP) {
@@ -104,7 +159,6 @@ fn plot_pred_result(pic_name: &str, dataset: &MakeMoonDataset, mlp: &MLP) {
             let x = x_vals[i];
             let y = y_vals[j];
             // 模拟分类器预测函数（替换为实际模型逻辑）
-            let score = x; // 示例逻辑：圆形边界
             if mlp.forward(&vec![Value::new(x), Value::new(y)])[0].data() > 0.0 {
                 pred_res_points.push((x, y));
             }
@@ -113,16 +167,13 @@ fn plot_pred_result(pic_name: &str, dataset: &MakeMoonDataset, mlp: &MLP) {
 
     // 绘制等高线
     // 绘制决策边界区域
-    let _ = chart.draw_series(LineSeries::new(
-        pred_res_points.iter().map(|&point| point),
-        &CYAN,
-    ));
+    let _ = chart.draw_series(LineSeries::new(pred_res_points.iter().copied(), &CYAN));
 
     chart
         .draw_series(
             pred_res_points
                 .iter()
-                .map(|&point| TriangleMarker::new(point, 10, &CYAN)),
+                .map(|&point| TriangleMarker::new(point, 10, CYAN)),
         )
         .unwrap();
 
@@ -147,7 +198,7 @@ fn plot_pred_result(pic_name: &str, dataset: &MakeMoonDataset, mlp: &MLP) {
         .draw_series(
             out_circ_points
                 .iter()
-                .map(|&point| TriangleMarker::new(point, 5, &RED)),
+                .map(|&point| TriangleMarker::new(point, 5, RED)),
         )
         .unwrap()
         .label("target 1.0");
@@ -156,7 +207,7 @@ fn plot_pred_result(pic_name: &str, dataset: &MakeMoonDataset, mlp: &MLP) {
         .draw_series(
             in_circ_points
                 .iter()
-                .map(|&point| TriangleMarker::new(point, 5, &BLUE)),
+                .map(|&point| TriangleMarker::new(point, 5, BLUE)),
         )
         .unwrap()
         .label("target -1.0");
@@ -164,7 +215,7 @@ fn plot_pred_result(pic_name: &str, dataset: &MakeMoonDataset, mlp: &MLP) {
     root_area.present().expect("Failed to save image");
 }
 
-#[warn(dead_code)]
+#[allow(dead_code)]
 fn test_grad() {
     let node1 = Value::new_with_name(1.0, "node1".to_string());
     let node2 = Value::new_with_name(3.0, "node2".to_string());
@@ -229,10 +280,13 @@ fn test_grad() {
 }
 
 /// svm "max-margin" loss ， its difficult ,because label is 1or0 not 1or-1
-fn cal_loss(out: &Vec<Value>, y: &Value) -> Value {
+fn cal_loss(out: &[Value], y: &Value) -> Result<Value> {
     if out.len() != 1 {
-        panic!("out.len()!=1");
+        return Err(Error::OutputSize {
+            expected: 1,
+            got: out.len(),
+        });
     }
-    let loss = &Value::new(1.0) - &(&out[0] * y);
-    loss.relu()
+    let loss = &Value::constant(1.0) - &(&out[0] * y);
+    Ok(loss.relu())
 }
@@ -0,0 +1,312 @@
+//! Character-level language model in the style of makemore: a word list is turned into
+//! (context, next char) examples, an `Embedding` followed by an `MLP` predicts the next char
+//! with softmax cross-entropy, and new words are sampled from the trained model.
+//!
+//! A context of one char is a bigram model. See `examples/makemore.rs`.
+
+use crate::error::{Error, Result};
+use crate::loss;
+use crate::nn::{Embedding, Module};
+use crate::{MLP, Prev, Value};
+use rand::Rng;
+use std::path::Path;
+
+/// Reads one word per line, skipping empty lines.
+pub fn read_words<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+/// The chars of a word list, index 0 is `.`, which marks the start and the end of a word.
+pub struct Vocab {
+    chars: Vec<char>,
+}
+
+impl Vocab {
+    pub const BOUNDARY: char = '.';
+
+    pub fn new(words: &[String]) -> Vocab {
+        let mut chars = words
+            .iter()
+            .flat_map(|w| w.chars())
+            .filter(|&c| c != Vocab::BOUNDARY)
+            .collect::<Vec<_>>();
+        chars.sort_unstable();
+        chars.dedup();
+        chars.insert(0, Vocab::BOUNDARY);
+        Vocab { chars }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    pub fn encode(&self, c: char) -> Option<usize> {
+        self.chars.iter().position(|&x| x == c)
+    }
+
+    pub fn decode(&self, index: usize) -> char {
+        self.chars[index]
+    }
+
+    /// (context, next char) pairs of every position of every word, the context is padded
+    /// with the boundary at the start of a word. The context needs at least one char.
+    pub fn examples(&self, words: &[String], context: usize) -> Result<Vec<(Vec<usize>, usize)>> {
+        check_context(context)?;
+        let mut examples = vec![];
+        for word in words.iter() {
+            let mut ctx = vec![0; context];
+            for c in word.chars().chain(std::iter::once(Vocab::BOUNDARY)) {
+                let next = self.encode(c).ok_or_else(|| {
+                    Error::InvalidArgument(format!("char {:?} is not in the vocabulary", c))
+                })?;
+                examples.push((ctx.clone(), next));
+                ctx.remove(0);
+                ctx.push(next);
+            }
+        }
+        Ok(examples)
+    }
+}
+
+/// Predicts the next char from the `context` previous ones.
+pub struct CharModel {
+    context: usize,
+    emb: Embedding,
+    mlp: MLP,
+}
+
+impl CharModel {
+    /// `hidden` are the sizes of the hidden layers of the MLP on top of the embeddings.
+    pub fn new(vocab_size: usize, context: usize, emb_dim: usize, hidden: &[usize]) -> CharModel {
+        CharModel::try_new(vocab_size, context, emb_dim, hidden).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Errors when the MLP would get no input, with an empty context or embeddings.
+    pub fn try_new(
+        vocab_size: usize,
+        context: usize,
+        emb_dim: usize,
+        hidden: &[usize],
+    ) -> Result<CharModel> {
+        check_context(context)?;
+        if emb_dim == 0 {
+            return Err(Error::InvalidArgument(
+                "the embeddings need at least one dimension".to_string(),
+            ));
+        }
+        let mut sizes = hidden.to_vec();
+        sizes.push(vocab_size);
+        Ok(CharModel {
+            context,
+            emb: Embedding::new(vocab_size, emb_dim),
+            mlp: MLP::new(context * emb_dim, &sizes),
+        })
+    }
+
+    pub fn context(&self) -> usize {
+        self.context
+    }
+
+    /// Unnormalized log-probabilities of the next char.
+    pub fn logits(&self, ctx: &[usize]) -> Result<Vec<Value>> {
+        let x = ctx
+            .iter()
+            .map(|&i| Value::constant(i as f64))
+            .collect::<Vec<_>>();
+        self.try_forward(&x)
+    }
+
+    /// Mean cross-entropy over `examples`.
+    pub fn loss(&self, examples: &[(Vec<usize>, usize)]) -> Result<Value> {
+        let mut losses = Vec::with_capacity(examples.len());
+        for (ctx, next) in examples.iter() {
+            losses.push(loss::try_cross_entropy(&self.logits(ctx)?, *next)?);
+        }
+        Ok(loss::mean(&losses))
+    }
+
+    /// Minibatch SGD for `steps` steps, returns the loss of each step.
+    pub fn fit<R: Rng>(
+        &self,
+        examples: &[(Vec<usize>, usize)],
+        steps: usize,
+        batch_size: usize,
+        learning_rate: f64,
+        rng: &mut R,
+    ) -> Result<Vec<f64>> {
+        if examples.is_empty() {
+            return Err(Error::InvalidArgument("no training examples".to_string()));
+        }
+        let mut losses = Vec::with_capacity(steps);
+        for _ in 0..steps {
+            let batch = (0..batch_size)
+                .map(|_| examples[rng.random_range(0..examples.len())].clone())
+                .collect::<Vec<_>>();
+            let loss = self.loss(&batch)?;
+            self.zero_grad();
+            loss.backward();
+            for p in self.parameters().iter() {
+                let p = p.value();
+                p.add_data(-learning_rate * p.grad());
+            }
+            losses.push(loss.data());
+        }
+        Ok(losses)
+    }
+
+    /// Samples a word char by char until the boundary or `max_len` chars.
+    /// A `temperature` below 1 makes the likely chars more likely, above 1 flattens the
+    /// distribution.
+    pub fn sample<R: Rng>(
+        &self,
+        vocab: &Vocab,
+        temperature: f64,
+        max_len: usize,
+        rng: &mut R,
+    ) -> Result<String> {
+        if temperature <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "temperature must be positive, got {}",
+                temperature
+            )));
+        }
+        let mut ctx = vec![0; self.context];
+        let mut word = String::new();
+        while word.chars().count() < max_len {
+            let logits = self
+                .logits(&ctx)?
+                .iter()
+                .map(|l| l.data() / temperature)
+                .collect::<Vec<_>>();
+            let next = sample_index(&logits, rng);
+            if next == 0 {
+                break;
+            }
+            word.push(vocab.decode(next));
+            ctx.remove(0);
+            ctx.push(next);
+        }
+        Ok(word)
+    }
+}
+
+fn check_context(context: usize) -> Result<()> {
+    if context == 0 {
+        return Err(Error::InvalidArgument(
+            "the context needs at least one char".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// index drawn from softmax(logits)
+fn sample_index<R: Rng>(logits: &[f64], rng: &mut R) -> usize {
+    let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let weights = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+    let mut r = rng.random::<f64>() * weights.iter().sum::<f64>();
+    for (i, w) in weights.iter().enumerate() {
+        if r < *w {
+            return i;
+        }
+        r -= w;
+    }
+    weights.len() - 1
+}
+
+impl Module for CharModel {
+    fn train(&self, mode: bool) {
+        self.emb.train(mode);
+        self.mlp.train(mode);
+    }
+
+    fn is_training(&self) -> bool {
+        self.mlp.is_training()
+    }
+
+    fn parameters(&self) -> Vec<Prev> {
+        let mut parameters = self.emb.parameters();
+        parameters.append(&mut self.mlp.parameters());
+        parameters
+    }
+
+    /// `x` are the indices of the context chars.
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        if x.len() != self.context {
+            return Err(Error::InputSize {
+                expected: self.context,
+                got: x.len(),
+            });
+        }
+        self.mlp.try_forward(&self.emb.try_forward(x)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn words() -> Vec<String> {
+        ["emma", "ava", "anna", "mia", "emily"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn vocab_and_examples() {
+        let vocab = Vocab::new(&words());
+        assert_eq!(vocab.len(), 1 + 8);
+        assert_eq!(vocab.decode(0), '.');
+        assert_eq!(vocab.decode(vocab.encode('m').unwrap()), 'm');
+
+        let examples = vocab.examples(&words()[..1], 2).unwrap();
+        // e, m, m, a, .
+        assert_eq!(examples.len(), 5);
+        let (e, m) = (vocab.encode('e').unwrap(), vocab.encode('m').unwrap());
+        assert_eq!(examples[0], (vec![0, 0], e));
+        assert_eq!(examples[2], (vec![e, m], m));
+        assert_eq!(examples[4].1, 0);
+        assert!(vocab.examples(&["bob".to_string()], 2).is_err());
+        assert!(matches!(
+            vocab.examples(&words(), 0),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn training_lowers_loss_and_samples() {
+        let words = words();
+        let vocab = Vocab::new(&words);
+        let examples = vocab.examples(&words, 2).unwrap();
+        let model = CharModel::new(vocab.len(), 2, 3, &[16]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let before = model.loss(&examples).unwrap().data();
+        model
+            .fit(&examples, 50, examples.len(), 0.2, &mut rng)
+            .unwrap();
+        let after = model.loss(&examples).unwrap().data();
+        assert!(after < before, "{} >= {}", after, before);
+
+        let word = model.sample(&vocab, 0.8, 10, &mut rng).unwrap();
+        assert!(word.chars().count() <= 10);
+        assert!(word.chars().all(|c| c != '.' && vocab.encode(c).is_some()));
+        assert!(model.sample(&vocab, 0.0, 10, &mut rng).is_err());
+        assert!(matches!(
+            CharModel::try_new(vocab.len(), 0, 3, &[4]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(CharModel::try_new(vocab.len(), 2, 0, &[4]).is_err());
+    }
+}
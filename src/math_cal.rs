@@ -1,19 +1,40 @@
-use crate::{Prev, Value};
+use crate::{Prev, Value, ValueInner, anomaly};
 use std::ops::{Add, Div, Mul, Sub};
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+impl Value {
+    /// Result node of an op, `prev` in operand order.
+    /// It only requires grad if one of its operands does.
+    pub(crate) fn from_op(data: f64, op: &str, prev: &[&Value]) -> Value {
+        let mut inner = ValueInner::new(data);
+        inner._op = op.to_string();
+        inner.requires_grad = prev.iter().any(|p| p.requires_grad());
+        inner._prev = prev.iter().map(|p| Prev(p.0.clone())).collect();
+        let out = Value(Arc::new(RwLock::new(inner)));
+        if anomaly::is_anomaly_detection_enabled()
+            && let Err(e) = anomaly::check_forward(&out.0)
+        {
+            panic!("{}", e);
+        }
+        out
+    }
+}
 
 impl Value {
     pub fn relu(&self) -> Value {
-        let out = Value::new_with_name(self.data().max(0.0), "ReLU".to_string());
-        out.0.borrow_mut()._prev.insert(Prev(self.0.clone()));
+        let out = Value::from_op(self.data().max(0.0), "ReLU", &[self]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|prev, g| {
+            let mask = if prev[0].data() > 0.0 { 1.0 } else { 0.0 };
+            vec![g * mask]
+        }));
 
-        let self_weak = Rc::downgrade(&self.0);
-        let out_weak = Rc::downgrade(&out.0);
-        out.0.borrow_mut()._backward = Some(Box::new(move || {
+        let self_weak = Arc::downgrade(&self.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
             if let (Some(self_rc), Some(out_rc)) = (self_weak.upgrade(), out_weak.upgrade()) {
-                let out_grad = out_rc.borrow().grad;
-                if out_grad >= 0.0 {
-                    self_rc.borrow_mut().grad += out_grad;
+                let out_grad = out_rc.read().unwrap().grad;
+                if out_rc.read().unwrap().data > 0.0 {
+                    self_rc.write().unwrap().add_grad(out_grad);
                 }
             }
         }));
@@ -24,20 +45,18 @@ impl Value {
 impl Add for &Value {
     type Output = Value;
     fn add(self, rhs: &Value) -> Value {
-        let out = Value::new(self.data() + rhs.data());
-        out.0.borrow_mut()._op = "+".to_string();
-        out.0.borrow_mut()._prev.insert(Prev(self.0.clone()));
-        out.0.borrow_mut()._prev.insert(Prev(rhs.0.clone()));
-        let self_weak = Rc::downgrade(&self.0);
-        let rhs_weak = Rc::downgrade(&rhs.0);
-        let out_weak = Rc::downgrade(&out.0);
-        out.0.borrow_mut()._backward = Some(Box::new(move || {
+        let out = Value::from_op(self.data() + rhs.data(), "+", &[self, rhs]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|_, g| vec![g.clone(), g.clone()]));
+        let self_weak = Arc::downgrade(&self.0);
+        let rhs_weak = Arc::downgrade(&rhs.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
             if let (Some(self_rc), Some(other_rc), Some(out_rc)) =
                 (self_weak.upgrade(), rhs_weak.upgrade(), out_weak.upgrade())
             {
-                let out_grad = out_rc.borrow().grad;
-                self_rc.borrow_mut().grad += out_grad;
-                other_rc.borrow_mut().grad += out_grad;
+                let out_grad = out_rc.read().unwrap().grad;
+                self_rc.write().unwrap().add_grad(out_grad);
+                other_rc.write().unwrap().add_grad(out_grad);
             }
         }));
         out
@@ -47,28 +66,27 @@ impl Add for &Value {
 impl<T: Into<f64>> Add<T> for &Value {
     type Output = Value;
     fn add(self, rhs: T) -> Value {
-        let rhs_value = rhs.into().into();
+        let rhs_value = Value::constant(rhs.into());
         self.add(&rhs_value)
     }
 }
 
 impl Sub for &Value {
     type Output = Value;
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn sub(self, rhs: &Value) -> Value {
-        let out = Value::new(self.data() - rhs.data());
-        out.0.borrow_mut()._op = "-".to_string();
-        out.0.borrow_mut()._prev.insert(Prev(self.0.clone()));
-        out.0.borrow_mut()._prev.insert(Prev(rhs.0.clone()));
-        let self_weak = Rc::downgrade(&self.0);
-        let rhs_weak = Rc::downgrade(&rhs.0);
-        let out_weak = Rc::downgrade(&out.0);
-        out.0.borrow_mut()._backward = Some(Box::new(move || {
+        let out = Value::from_op(self.data() - rhs.data(), "-", &[self, rhs]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|_, g| vec![g.clone(), g * -1.0]));
+        let self_weak = Arc::downgrade(&self.0);
+        let rhs_weak = Arc::downgrade(&rhs.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
             if let (Some(self_rc), Some(other_rc), Some(out_rc)) =
                 (self_weak.upgrade(), rhs_weak.upgrade(), out_weak.upgrade())
             {
-                let out_grad = out_rc.borrow().grad;
-                self_rc.borrow_mut().grad -= out_grad;
-                other_rc.borrow_mut().grad -= out_grad;
+                let out_grad = out_rc.read().unwrap().grad;
+                self_rc.write().unwrap().add_grad(out_grad);
+                other_rc.write().unwrap().add_grad(-out_grad);
             }
         }));
         out
@@ -78,31 +96,30 @@ impl Sub for &Value {
 impl<T: Into<f64>> Sub<T> for &Value {
     type Output = Value;
     fn sub(self, rhs: T) -> Value {
-        let rhs_value = rhs.into().into();
+        let rhs_value = Value::constant(rhs.into());
         self.sub(&rhs_value)
     }
 }
 
 impl Mul for &Value {
     type Output = Value;
-    //todo self 和rhs是同一个valueInner的话会panic
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn mul(self, rhs: &Value) -> Self::Output {
-        let out = Value::new(self.data() * rhs.data());
-        out.0.borrow_mut()._op = "*".to_string();
-        out.0.borrow_mut()._prev.insert(Prev(self.0.clone()));
-        out.0.borrow_mut()._prev.insert(Prev(rhs.0.clone()));
-        let self_weak = Rc::downgrade(&self.0);
-        let rhs_weak = Rc::downgrade(&rhs.0);
-        let out_weak = Rc::downgrade(&out.0);
-        out.0.borrow_mut()._backward = Some(Box::new(move || {
+        let out = Value::from_op(self.data() * rhs.data(), "*", &[self, rhs]);
+        out.0.write().unwrap()._grad_fn =
+            Some(Arc::new(|prev, g| vec![g * &prev[1], g * &prev[0]]));
+        let self_weak = Arc::downgrade(&self.0);
+        let rhs_weak = Arc::downgrade(&rhs.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
             if let (Some(self_rc), Some(other_rc), Some(out_rc)) =
                 (self_weak.upgrade(), rhs_weak.upgrade(), out_weak.upgrade())
             {
-                let other_data = other_rc.borrow().data;
-                let self_data = self_rc.borrow().data;
-                let out_grad = out_rc.borrow().grad;
-                self_rc.borrow_mut().grad += out_grad * other_data;
-                other_rc.borrow_mut().grad += out_grad * self_data;
+                let other_data = other_rc.read().unwrap().data;
+                let self_data = self_rc.read().unwrap().data;
+                let out_grad = out_rc.read().unwrap().grad;
+                self_rc.write().unwrap().add_grad(out_grad * other_data);
+                other_rc.write().unwrap().add_grad(out_grad * self_data);
             }
         }));
         out
@@ -122,7 +139,7 @@ impl Mul for &Value {
 impl<T: Into<f64>> Mul<T> for &Value {
     type Output = Value;
     fn mul(self, rhs: T) -> Value {
-        let rhs_value = rhs.into().into();
+        let rhs_value = Value::constant(rhs.into());
         self.mul(&rhs_value)
     }
 }
@@ -130,7 +147,7 @@ impl<T: Into<f64>> Mul<T> for &Value {
 impl Div for &Value {
     type Output = Value;
     fn div(self, rhs: &Value) -> Value {
-        let value2 = rhs.pow_i(&Value::new_with_name(-1.0, "pow -1.0".to_string()));
+        let value2 = rhs.pow_i(&Value::constant(-1.0));
         self.mul(&value2)
     }
 }
@@ -138,7 +155,7 @@ impl Div for &Value {
 impl<T: Into<f64>> Div<T> for &Value {
     type Output = Value;
     fn div(self, rhs: T) -> Value {
-        let rhs_value = rhs.into().into();
+        let rhs_value = Value::constant(rhs.into());
         self.div(&rhs_value)
     }
 }
@@ -152,31 +169,339 @@ impl From<f64> for Value {
 
 impl Value {
     pub fn pow_i(&self, rhs: &Value) -> Value {
-        let out = Value::new(self.data().powf(rhs.data()));
-        out.0.borrow_mut()._op = "pow".to_string();
-        out.0.borrow_mut()._prev.insert(Prev(self.0.clone()));
-        out.0.borrow_mut()._prev.insert(Prev(rhs.0.clone()));
-
-        let self_weak = Rc::downgrade(&self.0);
-        let rhs_weak = Rc::downgrade(&rhs.0);
-        let out_weak = Rc::downgrade(&out.0);
-        out.0.borrow_mut()._backward = Some(Box::new(move || {
+        let out = Value::from_op(self.data().powf(rhs.data()), "pow", &[self, rhs]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|prev, g| {
+            let (base, exp) = (&prev[0], &prev[1]);
+            let exp_grad = if exp.requires_grad() {
+                &(g * &base.pow_i(exp)) * &base.ln()
+            } else {
+                // a constant exponent, skip the ln that is NaN for a negative base
+                Value::constant(0.0)
+            };
+            vec![&(g * exp) * &base.pow_i(&(exp - 1.0)), exp_grad]
+        }));
+
+        let self_weak = Arc::downgrade(&self.0);
+        let rhs_weak = Arc::downgrade(&rhs.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
             if let (Some(self_rc), Some(other_rc), Some(out_rc)) =
                 (self_weak.upgrade(), rhs_weak.upgrade(), out_weak.upgrade())
             {
-                let out_grad = out_rc.borrow().grad;
-                let other_data = other_rc.borrow().data;
-                let mut sb = self_rc.borrow_mut();
-                let mut ob = other_rc.borrow_mut();
-                sb.grad += ob.data * sb.data.powf(other_data - 1.0) * out_grad;
-                ob.grad += sb.data.powf(other_data) * sb.data.ln() * out_grad;
+                let out_grad = out_rc.read().unwrap().grad;
+                let self_data = self_rc.read().unwrap().data;
+                let other_data = other_rc.read().unwrap().data;
+                // lock one node at a time, self and rhs may be the same node
+                self_rc
+                    .write()
+                    .unwrap()
+                    .add_grad(other_data * self_data.powf(other_data - 1.0) * out_grad);
+                other_rc
+                    .write()
+                    .unwrap()
+                    .add_grad(self_data.powf(other_data) * self_data.ln() * out_grad);
             }
         }));
         out
     }
 
     pub fn pow<T: Into<f64>>(&self, rhs: T) -> Value {
-        let x = rhs.into().into();
+        let x = Value::constant(rhs.into());
         self.pow_i(&x)
     }
+
+    pub fn exp(&self) -> Value {
+        let out = Value::from_op(self.data().exp(), "exp", &[self]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|prev, g| vec![g * &prev[0].exp()]));
+
+        let self_weak = Arc::downgrade(&self.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
+            if let (Some(self_rc), Some(out_rc)) = (self_weak.upgrade(), out_weak.upgrade()) {
+                let (out_data, out_grad) = {
+                    let out = out_rc.read().unwrap();
+                    (out.data, out.grad)
+                };
+                self_rc.write().unwrap().add_grad(out_data * out_grad);
+            }
+        }));
+        out
+    }
+
+    pub fn ln(&self) -> Value {
+        let out = Value::from_op(self.data().ln(), "ln", &[self]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|prev, g| vec![g / &prev[0]]));
+
+        let self_weak = Arc::downgrade(&self.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
+            if let (Some(self_rc), Some(out_rc)) = (self_weak.upgrade(), out_weak.upgrade()) {
+                let out_grad = out_rc.read().unwrap().grad;
+                let self_data = self_rc.read().unwrap().data;
+                self_rc.write().unwrap().add_grad(out_grad / self_data);
+            }
+        }));
+        out
+    }
+
+    pub fn tanh(&self) -> Value {
+        let out = Value::from_op(self.data().tanh(), "tanh", &[self]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|prev, g| {
+            let t = prev[0].tanh();
+            vec![g * &(&(&(&t * &t) * -1.0) + 1.0)]
+        }));
+
+        let self_weak = Arc::downgrade(&self.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
+            if let (Some(self_rc), Some(out_rc)) = (self_weak.upgrade(), out_weak.upgrade()) {
+                let (out_data, out_grad) = {
+                    let out = out_rc.read().unwrap();
+                    (out.data, out.grad)
+                };
+                self_rc
+                    .write()
+                    .unwrap()
+                    .add_grad((1.0 - out_data * out_data) * out_grad);
+            }
+        }));
+        out
+    }
+
+    pub fn sigmoid(&self) -> Value {
+        let out = Value::from_op(sigmoid(self.data()), "sigmoid", &[self]);
+        out.0.write().unwrap()._grad_fn = Some(Arc::new(|prev, g| {
+            let s = prev[0].sigmoid();
+            vec![g * &(&s * &(&(&s * -1.0) + 1.0))]
+        }));
+
+        let self_weak = Arc::downgrade(&self.0);
+        let out_weak = Arc::downgrade(&out.0);
+        out.0.write().unwrap()._backward = Some(Arc::new(move || {
+            if let (Some(self_rc), Some(out_rc)) = (self_weak.upgrade(), out_weak.upgrade()) {
+                let (out_data, out_grad) = {
+                    let out = out_rc.read().unwrap();
+                    (out.data, out.grad)
+                };
+                self_rc
+                    .write()
+                    .unwrap()
+                    .add_grad(out_data * (1.0 - out_data) * out_grad);
+            }
+        }));
+        out
+    }
+}
+
+/// Softmax of `xs`, computed with the max subtracted so large inputs do not overflow.
+///
+/// Each output is a single node over all the inputs, its gradient `s_i (δ_ij - s_j)` is
+/// computed directly instead of going through an `exp` and a division per element.
+pub fn softmax(xs: &[Value]) -> Vec<Value> {
+    let probs = Arc::new(softmax_data(xs));
+    let prev = xs.iter().collect::<Vec<_>>();
+    (0..xs.len())
+        .map(|i| {
+            let out = Value::from_op(probs[i], "softmax", &prev);
+            out.0.write().unwrap()._grad_fn = Some(Arc::new(move |prev, g| {
+                let s = softmax(prev);
+                let gs = g * &s[i];
+                (0..prev.len())
+                    .map(|j| {
+                        let ds = &(&gs * &s[j]) * -1.0;
+                        if i == j { &ds + &gs } else { ds }
+                    })
+                    .collect()
+            }));
+            let probs = probs.clone();
+            set_fused_backward(&out, xs, move |j| {
+                let delta = if i == j { 1.0 } else { 0.0 };
+                probs[i] * (delta - probs[j])
+            });
+            out
+        })
+        .collect()
+}
+
+/// `ln(softmax(xs))`, without the `ln` of a probability that underflows to 0.
+/// The gradient of the output `i` with respect to `x_j` is `δ_ij - softmax(xs)_j`.
+pub fn log_softmax(xs: &[Value]) -> Vec<Value> {
+    let max = xs
+        .iter()
+        .map(|x| x.data())
+        .fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = xs.iter().map(|x| (x.data() - max).exp()).sum::<f64>().ln() + max;
+    let probs = Arc::new(softmax_data(xs));
+    let prev = xs.iter().collect::<Vec<_>>();
+    xs.iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let out = Value::from_op(x.data() - log_sum, "log_softmax", &prev);
+            out.0.write().unwrap()._grad_fn = Some(Arc::new(move |prev, g| {
+                let s = softmax(prev);
+                (0..prev.len())
+                    .map(|j| {
+                        let ds = &(g * &s[j]) * -1.0;
+                        if i == j { &ds + g } else { ds }
+                    })
+                    .collect()
+            }));
+            let probs = probs.clone();
+            set_fused_backward(&out, xs, move |j| {
+                let delta = if i == j { 1.0 } else { 0.0 };
+                delta - probs[j]
+            });
+            out
+        })
+        .collect()
+}
+
+fn softmax_data(xs: &[Value]) -> Vec<f64> {
+    let max = xs
+        .iter()
+        .map(|x| x.data())
+        .fold(f64::NEG_INFINITY, f64::max);
+    let exps = xs
+        .iter()
+        .map(|x| (x.data() - max).exp())
+        .collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f64>();
+    exps.iter().map(|e| e / sum).collect()
+}
+
+// backward of an output over all of `xs`, `d(j)` is d out / d xs[j]
+fn set_fused_backward<F>(out: &Value, xs: &[Value], d: F)
+where
+    F: Fn(usize) -> f64 + Send + Sync + 'static,
+{
+    let xs_weak = xs.iter().map(|x| Arc::downgrade(&x.0)).collect::<Vec<_>>();
+    let out_weak = Arc::downgrade(&out.0);
+    out.0.write().unwrap()._backward = Some(Arc::new(move || {
+        let Some(out_rc) = out_weak.upgrade() else {
+            return;
+        };
+        let out_grad = out_rc.read().unwrap().grad;
+        for (j, x) in xs_weak.iter().enumerate() {
+            if let Some(x_rc) = x.upgrade() {
+                x_rc.write().unwrap().add_grad(out_grad * d(j));
+            }
+        }
+    }));
+}
+
+/// Does not overflow `exp` for large negative inputs.
+pub(crate) fn sigmoid(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autograd;
+
+    #[test]
+    fn relu_grad_follows_the_input() {
+        // a negative upstream grad still flows through an active ReLU
+        let x = Value::new(2.0);
+        let y = &x.relu() * -3.0;
+        y.backward();
+        assert_eq!(x.grad(), -3.0);
+
+        // and a positive one stops at an inactive one
+        let x = Value::new(-2.0);
+        let y = &x.relu() * 3.0;
+        y.backward();
+        assert_eq!(x.grad(), 0.0);
+    }
+
+    #[test]
+    fn sub_grads() {
+        let a = Value::new(5.0);
+        let b = Value::new(3.0);
+        let c = &a - &b;
+        c.backward();
+        assert_eq!(c.data(), 2.0);
+        assert_eq!(a.grad(), 1.0);
+        assert_eq!(b.grad(), -1.0);
+    }
+
+    // d sum_i w_i out_i / d x, out = f(x), by central differences
+    fn numeric_grad(f: fn(&[Value]) -> Vec<Value>, x: &[f64], w: &[f64]) -> Vec<f64> {
+        let h = 1e-6;
+        let loss = |x: &[f64]| {
+            let x = x.iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+            f(&x)
+                .iter()
+                .zip(w.iter())
+                .map(|(o, w)| o.data() * w)
+                .sum::<f64>()
+        };
+        (0..x.len())
+            .map(|i| {
+                let (mut plus, mut minus) = (x.to_vec(), x.to_vec());
+                plus[i] += h;
+                minus[i] -= h;
+                (loss(&plus) - loss(&minus)) / (2.0 * h)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn softmax_and_log_softmax_grads() {
+        let raw = [0.5, -1.0, 2.0, 0.1];
+        let w = [1.0, -2.0, 0.5, 3.0];
+        for f in [softmax, log_softmax] {
+            let x = raw.iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+            let out = f(&x);
+            let mut loss = Value::constant(0.0);
+            for (o, w) in out.iter().zip(w.iter()) {
+                loss = &loss + &(o * *w);
+            }
+            loss.backward();
+            for (x, g) in x.iter().zip(numeric_grad(f, &raw, &w)) {
+                assert!((x.grad() - g).abs() < 1e-6, "{} != {}", x.grad(), g);
+            }
+        }
+
+        let x = raw.iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+        let s = softmax(&x);
+        assert!((s.iter().map(|s| s.data()).sum::<f64>() - 1.0).abs() < 1e-12);
+        for (s, l) in s.iter().zip(log_softmax(&x)) {
+            assert!((s.data().ln() - l.data()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn log_softmax_is_stable() {
+        let x = [1000.0, -1000.0, 0.0].map(Value::new);
+        let out = log_softmax(&x);
+        assert_eq!(out[0].data(), 0.0);
+        assert_eq!(out[1].data(), -2000.0);
+        out[1].backward();
+        assert_eq!(x[0].grad(), -1.0);
+        assert_eq!(x[1].grad(), 1.0);
+        assert!(softmax(&x).iter().all(|s| s.data().is_finite()));
+    }
+
+    #[test]
+    fn softmax_second_derivative() {
+        // d2 s_0 / d x_0^2 = s_0 (1 - s_0) (1 - 2 s_0)
+        let x = [0.3, -0.2].map(Value::new);
+        let s0 = softmax(&x).remove(0);
+        let p = s0.data();
+        let g = autograd::grad(&s0, &[&x[0]], true).remove(0);
+        assert!((g.data() - p * (1.0 - p)).abs() < 1e-12);
+        let gg = autograd::grad(&g, &[&x[0]], false).remove(0);
+        assert!((gg.data() - p * (1.0 - p) * (1.0 - 2.0 * p)).abs() < 1e-12);
+
+        let l0 = log_softmax(&x).remove(0);
+        let g = autograd::grad(&l0, &[&x[0]], true).remove(0);
+        let gg = autograd::grad(&g, &[&x[0]], false).remove(0);
+        assert!((gg.data() + p * (1.0 - p)).abs() < 1e-12);
+    }
 }
@@ -1,19 +1,127 @@
-use crate::{Prev, Value};
-use rand::Rng;
+use crate::dual::Dual;
+use crate::error::{Error, Result};
+use crate::tape::Var;
+use crate::{Prev, Value, softmax};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::ops::Mul;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 pub trait Module {
     // fn forward(&self, x: &Value) -> Value;
 
     fn zero_grad(&self) {
         for p in self.parameters().iter() {
-            p.0.borrow_mut().grad = 0.0;
+            p.0.write().unwrap().grad = 0.0;
         }
     }
 
+    /// Registers `hook` on every parameter, see `Value::register_hook`.
+    fn register_hook<F>(&self, hook: F)
+    where
+        F: Fn(f64) -> f64 + Send + Sync + 'static,
+        Self: Sized,
+    {
+        let hook = Arc::new(hook);
+        for p in self.parameters().iter() {
+            let hook = hook.clone();
+            p.value().register_hook(move |g| hook(g));
+        }
+    }
+
+    fn clear_hooks(&self) {
+        for p in self.parameters().iter() {
+            p.value().clear_hooks();
+        }
+    }
+
+    /// Switches between training (the default) and evaluation mode. Containers pass it down to
+    /// their children, only modules like `Dropout` behave differently.
+    fn train(&self, _mode: bool) {}
+
+    fn eval(&self) {
+        self.train(false);
+    }
+
+    fn is_training(&self) -> bool {
+        true
+    }
+
     fn parameters(&self) -> Vec<Prev>;
 
-    fn forward(&self, x: &Vec<Value>) -> Vec<Value>;
+    /// `parameters()` with a name each, e.g. `layers.1.0.w.3` for the 4th weight of the 1st
+    /// neuron of the 2nd layer of an `MLP`. The default names are the indices.
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        self.parameters()
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| (i.to_string(), p))
+            .collect()
+    }
+
+    /// Stops the gradient of every parameter, see `Value::set_requires_grad`. Graphs built
+    /// afterwards skip the frozen parameters and the optimizers leave them as they are.
+    fn freeze(&self) {
+        for p in self.parameters().iter() {
+            p.value().set_requires_grad(false);
+        }
+    }
+
+    fn unfreeze(&self) {
+        for p in self.parameters().iter() {
+            p.value().set_requires_grad(true);
+        }
+    }
+
+    /// Like `try_forward`, but panics on an input of the wrong size.
+    // takes `&Vec` as it always did, so existing callers keep compiling
+    #[allow(clippy::ptr_arg)]
+    fn forward(&self, x: &Vec<Value>) -> Vec<Value> {
+        self.try_forward(x).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Forward pass, with an error instead of a panic on an input of the wrong size.
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>>;
+
+    /// Forward pass over a batch of samples. Only needed by modules that mix samples, like
+    /// `BatchNorm1d`, the default runs `forward` on each sample.
+    fn forward_batch(&self, xs: &[Vec<Value>]) -> Vec<Vec<Value>> {
+        self.try_forward_batch(xs)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_forward_batch(&self, xs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        xs.iter().map(|x| self.try_forward(x)).collect()
+    }
+
+    /// The data of every parameter, in `parameters()` order.
+    fn parameters_data(&self) -> Vec<f64> {
+        self.parameters().iter().map(|p| p.value().data()).collect()
+    }
+
+    /// Loads parameters saved with `parameters_data`.
+    fn load_parameters(&self, data: &[f64]) -> Result<()> {
+        let params = self.parameters();
+        if params.len() != data.len() {
+            return Err(Error::ParameterCount {
+                expected: params.len(),
+                got: data.len(),
+            });
+        }
+        for (p, d) in params.iter().zip(data.iter()) {
+            p.value().set_data(*d);
+        }
+        Ok(())
+    }
+}
+
+// names of a child module, under `prefix`
+fn prefixed(prefix: &str, named: Vec<(String, Prev)>) -> Vec<(String, Prev)> {
+    named
+        .into_iter()
+        .map(|(name, p)| (format!("{}.{}", prefix, name), p))
+        .collect()
 }
 
 struct Neuron {
@@ -37,6 +145,36 @@ impl Neuron {
     }
 }
 
+impl Neuron {
+    fn n_params(&self) -> usize {
+        self.w.len() + 1
+    }
+
+    fn forward_tape<'t>(&self, params: &[Var<'t>], x: &[Var<'t>]) -> Var<'t> {
+        let (w, b) = params.split_at(self.w.len());
+        let mut out = w[0] * x[0];
+        for (w, x) in w.iter().zip(x.iter()).skip(1) {
+            out = out + *w * *x;
+        }
+        out = out + b[0];
+        if self.non_lin {
+            out = out.relu();
+        }
+        out
+    }
+
+    fn forward_dual(&self, x: &[Dual]) -> Dual {
+        let mut out = Dual::constant(self.b.data());
+        for (w, x) in self.w.iter().zip(x.iter()) {
+            out = out + x * w.data();
+        }
+        if self.non_lin {
+            out = out.relu();
+        }
+        out
+    }
+}
+
 impl Module for Neuron {
     fn parameters(&self) -> Vec<Prev> {
         let mut vec = vec![];
@@ -47,35 +185,84 @@ impl Module for Neuron {
         vec
     }
 
-    fn forward(&self, x: &Vec<Value>) -> Vec<Value> {
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
         if x.len() != self.w.len() {
-            panic!("x.len() != self.w.len()");
+            return Err(Error::InputSize {
+                expected: self.w.len(),
+                got: x.len(),
+            });
         }
         let mut out = self.w[0].mul(&x[0]);
-        for i in 1..x.len() {
-            out = &out + &self.w[i].mul(&x[i]);
+        for (w, x) in self.w.iter().zip(x.iter()).skip(1) {
+            out = &out + &w.mul(x);
         }
         out = &out + &self.b;
         if self.non_lin {
             out = out.relu();
         }
-        vec![out]
+        Ok(vec![out])
     }
 }
 
-struct Layer {
+/// A fully connected layer, with a ReLU when `non_lin`.
+pub struct Layer {
     ns: Vec<Neuron>,
+    training: AtomicBool,
 }
 impl Layer {
     pub fn new(n_inputs: usize, n_outputs: usize, non_lin: bool) -> Layer {
         let ns = (0..n_outputs)
             .map(|_| Neuron::new(n_inputs, non_lin))
             .collect::<Vec<_>>();
-        Layer { ns }
+        Layer {
+            ns,
+            training: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Layer {
+    fn n_params(&self) -> usize {
+        self.ns.iter().map(|n| n.n_params()).sum()
+    }
+
+    fn forward_tape<'t>(&self, params: &[Var<'t>], x: &[Var<'t>]) -> Vec<Var<'t>> {
+        let mut offset = 0;
+        let mut out = vec![];
+        for one in self.ns.iter() {
+            let n = one.n_params();
+            out.push(one.forward_tape(&params[offset..offset + n], x));
+            offset += n;
+        }
+        out
+    }
+
+    fn forward_dual(&self, x: &[Dual]) -> Vec<Dual> {
+        self.ns.iter().map(|n| n.forward_dual(x)).collect()
     }
 }
 
 impl Module for Layer {
+    fn train(&self, mode: bool) {
+        self.training.store(mode, Ordering::Relaxed);
+    }
+
+    fn is_training(&self) -> bool {
+        self.training.load(Ordering::Relaxed)
+    }
+
+    /// `{neuron}.w.{input}` and `{neuron}.b`.
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        let mut named = vec![];
+        for (n, neuron) in self.ns.iter().enumerate() {
+            for (i, w) in neuron.w.iter().enumerate() {
+                named.push((format!("{}.w.{}", n, i), Prev(w.0.clone())));
+            }
+            named.push((format!("{}.b", n), Prev(neuron.b.0.clone())));
+        }
+        named
+    }
+
     fn parameters(&self) -> Vec<Prev> {
         let mut vec = vec![];
         for one in self.ns.iter() {
@@ -85,18 +272,19 @@ impl Module for Layer {
         vec
     }
 
-    fn forward(&self, x: &Vec<Value>) -> Vec<Value> {
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
         let mut out = vec![];
         for one in self.ns.iter() {
-            let mut neuron_res = one.forward(x);
+            let mut neuron_res = one.try_forward(x)?;
             out.append(&mut neuron_res)
         }
-        out
+        Ok(out)
     }
 }
 
 pub struct MLP {
     layers: Vec<Layer>,
+    training: AtomicBool,
 }
 
 impl MLP {
@@ -113,10 +301,53 @@ impl MLP {
             }
             layers.push(Layer::new(in_num, out_num, non_line));
         }
-        MLP { layers }
+        MLP {
+            layers,
+            training: AtomicBool::new(true),
+        }
+    }
+}
+impl MLP {
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Same as `forward` but records the graph on a `Tape`.
+    ///
+    /// `params` are the tape vars of `self.parameters()`, in order, see `Tape::load`.
+    pub fn forward_tape<'t>(&self, params: &[Var<'t>], x: &[Var<'t>]) -> Vec<Var<'t>> {
+        let mut offset = 0;
+        let mut y = x.to_vec();
+        for layer in self.layers.iter() {
+            let n = layer.n_params();
+            y = layer.forward_tape(&params[offset..offset + n], &y);
+            offset += n;
+        }
+        y
+    }
+
+    /// Forward mode pass, the parameters are treated as constants.
+    /// Use with `dual::jvp` to get the sensitivity of the outputs to the inputs.
+    pub fn forward_dual(&self, x: &[Dual]) -> Vec<Dual> {
+        let mut y = x.to_vec();
+        for layer in self.layers.iter() {
+            y = layer.forward_dual(&y);
+        }
+        y
     }
 }
 impl Module for MLP {
+    fn train(&self, mode: bool) {
+        self.training.store(mode, Ordering::Relaxed);
+        for layer in self.layers.iter() {
+            layer.train(mode);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training.load(Ordering::Relaxed)
+    }
+
     fn parameters(&self) -> Vec<Prev> {
         let mut parameters = vec![];
         for layer in self.layers.iter() {
@@ -126,14 +357,1392 @@ impl Module for MLP {
         parameters
     }
 
-    fn forward(&self, x: &Vec<Value>) -> Vec<Value> {
+    /// `layers.{layer}.` followed by the names of `Layer`.
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        let mut named = vec![];
+        for (l, layer) in self.layers.iter().enumerate() {
+            named.append(&mut prefixed(
+                &format!("layers.{}", l),
+                layer.named_parameters(),
+            ));
+        }
+        named
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
         let mut y = vec![];
         for one in x {
             y.push((*one).clone())
         }
         for layer in self.layers.iter() {
-            y = layer.forward(&y);
+            y = layer.try_forward(&y)?;
         }
-        y
+        Ok(y)
+    }
+}
+
+/// Modules applied one after the other, the outputs of each are the inputs of the next.
+pub struct Sequential {
+    modules: Vec<Box<dyn Module + Send + Sync>>,
+    training: AtomicBool,
+}
+
+impl Sequential {
+    pub fn new(modules: Vec<Box<dyn Module + Send + Sync>>) -> Sequential {
+        Sequential {
+            modules,
+            training: AtomicBool::new(true),
+        }
+    }
+
+    pub fn push<M: Module + Send + Sync + 'static>(&mut self, module: M) {
+        module.train(self.is_training());
+        self.modules.push(Box::new(module));
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&(dyn Module + Send + Sync)> {
+        self.modules.get(index).map(|m| m.as_ref())
+    }
+}
+
+impl Module for Sequential {
+    fn train(&self, mode: bool) {
+        self.training.store(mode, Ordering::Relaxed);
+        for m in self.modules.iter() {
+            m.train(mode);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training.load(Ordering::Relaxed)
+    }
+
+    fn parameters(&self) -> Vec<Prev> {
+        let mut parameters = vec![];
+        for m in self.modules.iter() {
+            parameters.append(&mut m.parameters());
+        }
+        parameters
+    }
+
+    /// `{module}.` followed by the names of the module.
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        let mut named = vec![];
+        for (i, m) in self.modules.iter().enumerate() {
+            named.append(&mut prefixed(&i.to_string(), m.named_parameters()));
+        }
+        named
+    }
+
+    fn freeze(&self) {
+        for m in self.modules.iter() {
+            m.freeze();
+        }
+    }
+
+    fn unfreeze(&self) {
+        for m in self.modules.iter() {
+            m.unfreeze();
+        }
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        let mut y = x.iter().map(|v| v.clone()).collect::<Vec<_>>();
+        for m in self.modules.iter() {
+            y = m.try_forward(&y)?;
+        }
+        Ok(y)
+    }
+
+    fn try_forward_batch(&self, xs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        let mut ys = xs
+            .iter()
+            .map(|x| x.iter().map(|v| v.clone()).collect())
+            .collect::<Vec<Vec<Value>>>();
+        for m in self.modules.iter() {
+            ys = m.try_forward_batch(&ys)?;
+        }
+        Ok(ys)
+    }
+}
+
+/// Zeroes each input with probability `p` in training mode and scales the others by
+/// `1 / (1 - p)`, so the expected activation is unchanged. Identity in evaluation mode.
+pub struct Dropout {
+    p: f64,
+    training: AtomicBool,
+    rng: Mutex<StdRng>,
+}
+
+impl Dropout {
+    pub fn new(p: f64) -> Dropout {
+        Dropout::with_rng(p, StdRng::from_os_rng())
+    }
+
+    /// Same masks for the same seed, for reproducible runs.
+    pub fn new_with_seed(p: f64, seed: u64) -> Dropout {
+        Dropout::with_rng(p, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(p: f64, rng: StdRng) -> Dropout {
+        assert!(
+            (0.0..=1.0).contains(&p),
+            "dropout probability must be in [0, 1], got {}",
+            p
+        );
+        Dropout {
+            p,
+            training: AtomicBool::new(true),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn p(&self) -> f64 {
+        self.p
+    }
+}
+
+impl Module for Dropout {
+    fn train(&self, mode: bool) {
+        self.training.store(mode, Ordering::Relaxed);
+    }
+
+    fn is_training(&self) -> bool {
+        self.training.load(Ordering::Relaxed)
+    }
+
+    fn parameters(&self) -> Vec<Prev> {
+        vec![]
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        if !self.is_training() || self.p == 0.0 {
+            return Ok(x.iter().map(|v| v.clone()).collect());
+        }
+        let mut rng = self.rng.lock().unwrap();
+        let scale = 1.0 / (1.0 - self.p);
+        Ok(x.iter()
+            .map(|v| {
+                if rng.random::<f64>() < self.p {
+                    Value::constant(0.0)
+                } else {
+                    v * scale
+                }
+            })
+            .collect())
+    }
+}
+
+// the trainable per-feature scale and shift of the norm layers, empty without `affine`
+struct Affine {
+    gamma: Vec<Value>,
+    beta: Vec<Value>,
+}
+
+impl Affine {
+    fn new(num_features: usize, affine: bool) -> Affine {
+        if !affine {
+            return Affine {
+                gamma: vec![],
+                beta: vec![],
+            };
+        }
+        Affine {
+            gamma: (0..num_features).map(|_| Value::new(1.0)).collect(),
+            beta: (0..num_features).map(|_| Value::new(0.0)).collect(),
+        }
+    }
+
+    fn apply(&self, j: usize, x_hat: Value) -> Value {
+        if self.gamma.is_empty() {
+            x_hat
+        } else {
+            &(&x_hat * &self.gamma[j]) + &self.beta[j]
+        }
+    }
+
+    fn parameters(&self) -> Vec<Prev> {
+        self.gamma
+            .iter()
+            .chain(self.beta.iter())
+            .map(|p| Prev(p.0.clone()))
+            .collect()
+    }
+
+    // `gamma.{feature}` and `beta.{feature}`
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        let gamma = self
+            .gamma
+            .iter()
+            .enumerate()
+            .map(|(j, p)| (format!("gamma.{}", j), p));
+        let beta = self
+            .beta
+            .iter()
+            .enumerate()
+            .map(|(j, p)| (format!("beta.{}", j), p));
+        gamma
+            .chain(beta)
+            .map(|(name, p)| (name, Prev(p.0.clone())))
+            .collect()
+    }
+}
+
+fn check_norm_options(layer: &str, num_features: usize, eps: f64) -> Result<()> {
+    if num_features == 0 {
+        return Err(Error::InvalidArgument(format!(
+            "{} needs at least one feature",
+            layer
+        )));
+    }
+    if eps.is_nan() || eps <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "{} eps must be positive, got {}",
+            layer, eps
+        )));
+    }
+    Ok(())
+}
+
+/// Normalizes each feature over the batch, then scales and shifts it by the trainable `gamma`
+/// and `beta` when `affine`.
+///
+/// In training mode it needs `forward_batch` and updates the running mean and variance with
+/// `momentum`, in evaluation mode they are used instead of the batch statistics.
+pub struct BatchNorm1d {
+    num_features: usize,
+    eps: f64,
+    momentum: f64,
+    affine: Affine,
+    running_mean: Mutex<Vec<f64>>,
+    running_var: Mutex<Vec<f64>>,
+    training: AtomicBool,
+}
+
+impl BatchNorm1d {
+    pub fn new(num_features: usize) -> BatchNorm1d {
+        BatchNorm1d::new_with_options(num_features, 0.1, 1e-5, true)
+    }
+
+    pub fn new_with_options(
+        num_features: usize,
+        momentum: f64,
+        eps: f64,
+        affine: bool,
+    ) -> BatchNorm1d {
+        BatchNorm1d::try_new_with_options(num_features, momentum, eps, affine)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(num_features: usize) -> Result<BatchNorm1d> {
+        BatchNorm1d::try_new_with_options(num_features, 0.1, 1e-5, true)
+    }
+
+    /// Errors without features, with an `eps` that is not positive or a `momentum` outside
+    /// [0, 1].
+    pub fn try_new_with_options(
+        num_features: usize,
+        momentum: f64,
+        eps: f64,
+        affine: bool,
+    ) -> Result<BatchNorm1d> {
+        check_norm_options("BatchNorm1d", num_features, eps)?;
+        if !(0.0..=1.0).contains(&momentum) {
+            return Err(Error::InvalidArgument(format!(
+                "BatchNorm1d momentum must be in [0, 1], got {}",
+                momentum
+            )));
+        }
+        Ok(BatchNorm1d {
+            num_features,
+            eps,
+            momentum,
+            affine: Affine::new(num_features, affine),
+            running_mean: Mutex::new(vec![0.0; num_features]),
+            running_var: Mutex::new(vec![1.0; num_features]),
+            training: AtomicBool::new(true),
+        })
+    }
+
+    pub fn running_mean(&self) -> Vec<f64> {
+        self.running_mean.lock().unwrap().clone()
+    }
+
+    pub fn running_var(&self) -> Vec<f64> {
+        self.running_var.lock().unwrap().clone()
+    }
+
+    fn check_input(&self, x: &[Value]) -> Result<()> {
+        if x.len() != self.num_features {
+            return Err(Error::InputSize {
+                expected: self.num_features,
+                got: x.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Module for BatchNorm1d {
+    fn train(&self, mode: bool) {
+        self.training.store(mode, Ordering::Relaxed);
+    }
+
+    fn is_training(&self) -> bool {
+        self.training.load(Ordering::Relaxed)
+    }
+
+    fn parameters(&self) -> Vec<Prev> {
+        self.affine.parameters()
+    }
+
+    /// `gamma.{feature}` and `beta.{feature}`.
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        self.affine.named_parameters()
+    }
+
+    /// A single sample, only in evaluation mode.
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        if self.is_training() {
+            return Err(Error::InvalidArgument(
+                "BatchNorm1d needs a batch in training mode, use forward_batch".to_string(),
+            ));
+        }
+        self.check_input(x)?;
+        let mean = self.running_mean.lock().unwrap();
+        let var = self.running_var.lock().unwrap();
+        Ok(x.iter()
+            .enumerate()
+            .map(|(j, x)| {
+                self.affine
+                    .apply(j, &(x - mean[j]) * (1.0 / (var[j] + self.eps).sqrt()))
+            })
+            .collect())
+    }
+
+    fn try_forward_batch(&self, xs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        if !self.is_training() {
+            return xs.iter().map(|x| self.try_forward(x)).collect();
+        }
+        if xs.len() < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "BatchNorm1d needs more than one sample in training mode, got {}",
+                xs.len()
+            )));
+        }
+        for x in xs.iter() {
+            self.check_input(x)?;
+        }
+
+        let n = xs.len() as f64;
+        let mut ys = (0..xs.len())
+            .map(|_| Vec::with_capacity(self.num_features))
+            .collect::<Vec<_>>();
+        let mut running_mean = self.running_mean.lock().unwrap();
+        let mut running_var = self.running_var.lock().unwrap();
+        for j in 0..self.num_features {
+            let mut sum = xs[0][j].clone();
+            for x in xs.iter().skip(1) {
+                sum = &sum + &x[j];
+            }
+            let mean = &sum / n;
+            let centered = xs.iter().map(|x| &x[j] - &mean).collect::<Vec<_>>();
+            let mut sq_sum = centered[0].pow(2.0);
+            for c in centered.iter().skip(1) {
+                sq_sum = &sq_sum + &c.pow(2.0);
+            }
+            // biased variance to normalize, unbiased one for the running estimate
+            let var = &sq_sum / n;
+            let inv_std = (&var + self.eps).pow(-0.5);
+            for (y, c) in ys.iter_mut().zip(centered.iter()) {
+                y.push(self.affine.apply(j, c * &inv_std));
+            }
+
+            let m = self.momentum;
+            running_mean[j] = (1.0 - m) * running_mean[j] + m * mean.data();
+            running_var[j] = (1.0 - m) * running_var[j] + m * var.data() * n / (n - 1.0);
+        }
+        Ok(ys)
+    }
+}
+
+/// Normalizes the features of each sample to zero mean and unit variance, then scales and
+/// shifts them by the trainable `gamma` and `beta` when `affine`. Same in training and
+/// evaluation mode.
+pub struct LayerNorm {
+    dim: usize,
+    eps: f64,
+    affine: Affine,
+}
+
+impl LayerNorm {
+    pub fn new(dim: usize) -> LayerNorm {
+        LayerNorm::new_with_options(dim, 1e-5, true)
+    }
+
+    pub fn new_with_options(dim: usize, eps: f64, affine: bool) -> LayerNorm {
+        LayerNorm::try_new_with_options(dim, eps, affine).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(dim: usize) -> Result<LayerNorm> {
+        LayerNorm::try_new_with_options(dim, 1e-5, true)
+    }
+
+    /// Errors with a zero `dim` or an `eps` that is not positive.
+    pub fn try_new_with_options(dim: usize, eps: f64, affine: bool) -> Result<LayerNorm> {
+        check_norm_options("LayerNorm", dim, eps)?;
+        Ok(LayerNorm {
+            dim,
+            eps,
+            affine: Affine::new(dim, affine),
+        })
+    }
+}
+
+impl Module for LayerNorm {
+    fn parameters(&self) -> Vec<Prev> {
+        self.affine.parameters()
+    }
+
+    /// `gamma.{feature}` and `beta.{feature}`.
+    fn named_parameters(&self) -> Vec<(String, Prev)> {
+        self.affine.named_parameters()
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        if x.len() != self.dim {
+            return Err(Error::InputSize {
+                expected: self.dim,
+                got: x.len(),
+            });
+        }
+        let n = self.dim as f64;
+        let mut sum = x[0].clone();
+        for x in x.iter().skip(1) {
+            sum = &sum + x;
+        }
+        let mean = &sum / n;
+        let centered = x.iter().map(|x| x - &mean).collect::<Vec<_>>();
+        let mut sq_sum = centered[0].pow(2.0);
+        for c in centered.iter().skip(1) {
+            sq_sum = &sq_sum + &c.pow(2.0);
+        }
+        let inv_std = (&(&sq_sum / n) + self.eps).pow(-0.5);
+        Ok(centered
+            .iter()
+            .enumerate()
+            .map(|(j, c)| self.affine.apply(j, c * &inv_std))
+            .collect())
+    }
+}
+
+/// A table of `num_embeddings` trainable vectors of size `dim`, for categorical features.
+///
+/// `forward` takes indices as values (integers stored in `f64`) and returns their vectors one
+/// after the other. The row of `padding_idx` is all zeros and never gets a gradient. With
+/// `max_norm` the looked up rows with a larger norm are rescaled in place to it.
+pub struct Embedding {
+    weight: Vec<Vec<Value>>,
+    dim: usize,
+    padding_idx: Option<usize>,
+    max_norm: Option<f64>,
+}
+
+impl Embedding {
+    pub fn new(num_embeddings: usize, dim: usize) -> Embedding {
+        Embedding::new_with_options(num_embeddings, dim, None, None)
+    }
+
+    pub fn new_with_options(
+        num_embeddings: usize,
+        dim: usize,
+        padding_idx: Option<usize>,
+        max_norm: Option<f64>,
+    ) -> Embedding {
+        Embedding::try_new_with_options(num_embeddings, dim, padding_idx, max_norm)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Errors when `padding_idx` is not a row of the table or `max_norm` is not positive.
+    pub fn try_new_with_options(
+        num_embeddings: usize,
+        dim: usize,
+        padding_idx: Option<usize>,
+        max_norm: Option<f64>,
+    ) -> Result<Embedding> {
+        if let Some(idx) = padding_idx.filter(|&idx| idx >= num_embeddings) {
+            return Err(Error::InvalidArgument(format!(
+                "padding_idx {} out of range for {} embeddings",
+                idx, num_embeddings
+            )));
+        }
+        if let Some(max_norm) = max_norm.filter(|m| m.is_nan() || *m <= 0.0) {
+            return Err(Error::InvalidArgument(format!(
+                "max_norm must be positive, got {}",
+                max_norm
+            )));
+        }
+        let mut rng = rand::rng();
+        let weight = (0..num_embeddings)
+            .map(|i| {
+                (0..dim)
+                    .map(|_| {
+                        if padding_idx == Some(i) {
+                            Value::constant(0.0)
+                        } else {
+                            Value::new(rng.random_range(-1.0..=1.0))
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(Embedding {
+            weight,
+            dim,
+            padding_idx,
+            max_norm,
+        })
+    }
+
+    pub fn num_embeddings(&self) -> usize {
+        self.weight.len()
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn padding_idx(&self) -> Option<usize> {
+        self.padding_idx
+    }
+
+    /// The vector of `index`.
+    pub fn lookup(&self, index: usize) -> Vec<Value> {
+        self.try_lookup(index).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_lookup(&self, index: usize) -> Result<Vec<Value>> {
+        let row = self.weight.get(index).ok_or(Error::IndexOutOfRange {
+            index,
+            len: self.weight.len(),
+        })?;
+        if let Some(max_norm) = self.max_norm {
+            let norm = row.iter().map(|v| v.data().powi(2)).sum::<f64>().sqrt();
+            if norm > max_norm {
+                let scale = max_norm / (norm + 1e-7);
+                for v in row.iter() {
+                    v.set_data(v.data() * scale);
+                }
+            }
+        }
+        Ok(row.iter().map(|v| v.clone()).collect())
+    }
+}
+
+impl Module for Embedding {
+    /// Every row, the padding one included so the layout does not depend on it.
+    fn parameters(&self) -> Vec<Prev> {
+        self.weight
+            .iter()
+            .flatten()
+            .map(|p| Prev(p.0.clone()))
+            .collect()
+    }
+
+    /// The padding row stays frozen.
+    fn unfreeze(&self) {
+        for (i, row) in self.weight.iter().enumerate() {
+            if self.padding_idx != Some(i) {
+                row.iter().for_each(|v| v.set_requires_grad(true));
+            }
+        }
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        let mut out = Vec::with_capacity(x.len() * self.dim);
+        for x in x.iter() {
+            let index = x.data();
+            if index < 0.0 || index.fract() != 0.0 {
+                return Err(Error::InvalidArgument(format!(
+                    "embedding index must be a non-negative integer, got {}",
+                    index
+                )));
+            }
+            out.append(&mut self.try_lookup(index as usize)?);
+        }
+        Ok(out)
+    }
+}
+
+fn dot(a: &[Value], b: &[Value]) -> Value {
+    let mut out = &a[0] * &b[0];
+    for (a, b) in a.iter().zip(b.iter()).skip(1) {
+        out = &out + &(a * b);
+    }
+    out
+}
+
+// a flat input of `len * dim` values as `len` rows
+fn split_rows(x: &[Value], dim: usize) -> Result<Vec<Vec<Value>>> {
+    if dim == 0 || !x.len().is_multiple_of(dim) {
+        return Err(Error::InvalidArgument(format!(
+            "input of size {} is not a sequence of vectors of size {}",
+            x.len(),
+            dim
+        )));
+    }
+    Ok(x.chunks(dim)
+        .map(|row| row.iter().map(|v| v.clone()).collect())
+        .collect())
+}
+
+fn check_rows(xs: &[Vec<Value>], dim: usize) -> Result<()> {
+    for x in xs.iter() {
+        if x.len() != dim {
+            return Err(Error::InputSize {
+                expected: dim,
+                got: x.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Per head, per query position, the weight of each key position.
+pub type AttentionWeights = Vec<Vec<Vec<f64>>>;
+
+/// Multi-head scaled dot-product self-attention over a sequence of `d_model` vectors.
+///
+/// Each head attends with `softmax(q k^T / sqrt(d_head)) v` on its slice of the projected
+/// queries, keys and values, the heads are concatenated and projected back. With `causal` a
+/// position only attends to itself and the positions before it.
+///
+/// Use `forward_seq`, as a `Module` the sequence is passed flattened.
+pub struct MultiHeadAttention {
+    d_model: usize,
+    n_heads: usize,
+    causal: bool,
+    wq: Layer,
+    wk: Layer,
+    wv: Layer,
+    wo: Layer,
+}
+
+impl MultiHeadAttention {
+    pub fn new(d_model: usize, n_heads: usize, causal: bool) -> MultiHeadAttention {
+        assert!(
+            n_heads > 0 && d_model.is_multiple_of(n_heads),
+            "d_model {} is not a multiple of n_heads {}",
+            d_model,
+            n_heads
+        );
+        MultiHeadAttention {
+            d_model,
+            n_heads,
+            causal,
+            wq: Layer::new(d_model, d_model, false),
+            wk: Layer::new(d_model, d_model, false),
+            wv: Layer::new(d_model, d_model, false),
+            wo: Layer::new(d_model, d_model, false),
+        }
+    }
+
+    pub fn forward_seq(&self, xs: &[Vec<Value>]) -> Vec<Vec<Value>> {
+        self.try_forward_seq(xs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_forward_seq(&self, xs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        Ok(self.attend(xs)?.0)
+    }
+
+    /// Attention weights of each head, `out[h][i][j]` is how much position `i` attends to `j`.
+    pub fn attention_weights(&self, xs: &[Vec<Value>]) -> Result<AttentionWeights> {
+        Ok(self.attend(xs)?.1)
+    }
+
+    fn attend(&self, xs: &[Vec<Value>]) -> Result<(Vec<Vec<Value>>, AttentionWeights)> {
+        check_rows(xs, self.d_model)?;
+        let project = |w: &Layer| -> Result<Vec<Vec<Value>>> {
+            xs.iter().map(|x| w.try_forward(x)).collect()
+        };
+        let (q, k, v) = (project(&self.wq)?, project(&self.wk)?, project(&self.wv)?);
+
+        let d_head = self.d_model / self.n_heads;
+        let scale = 1.0 / (d_head as f64).sqrt();
+        let mut heads = (0..xs.len())
+            .map(|_| Vec::with_capacity(self.d_model))
+            .collect::<Vec<Vec<Value>>>();
+        let mut weights = Vec::with_capacity(self.n_heads);
+        for h in 0..self.n_heads {
+            let range = h * d_head..(h + 1) * d_head;
+            let mut head_weights = Vec::with_capacity(xs.len());
+            for (i, out) in heads.iter_mut().enumerate() {
+                // masked positions are left out of the softmax, the same as a -inf score
+                let visible = if self.causal { i + 1 } else { xs.len() };
+                let scores = (0..visible)
+                    .map(|j| &dot(&q[i][range.clone()], &k[j][range.clone()]) * scale)
+                    .collect::<Vec<_>>();
+                let w = softmax(&scores);
+                for d in range.clone() {
+                    let mut acc = &w[0] * &v[0][d];
+                    for (j, w) in w.iter().enumerate().skip(1) {
+                        acc = &acc + &(w * &v[j][d]);
+                    }
+                    out.push(acc);
+                }
+                let mut row = w.iter().map(|w| w.data()).collect::<Vec<_>>();
+                row.resize(xs.len(), 0.0);
+                head_weights.push(row);
+            }
+            weights.push(head_weights);
+        }
+        let out = heads
+            .iter()
+            .map(|h| self.wo.try_forward(h))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((out, weights))
+    }
+}
+
+impl Module for MultiHeadAttention {
+    fn parameters(&self) -> Vec<Prev> {
+        let mut parameters = vec![];
+        for layer in [&self.wq, &self.wk, &self.wv, &self.wo] {
+            parameters.append(&mut layer.parameters());
+        }
+        parameters
+    }
+
+    /// `x` is the sequence flattened, `len * d_model` values.
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        Ok(self
+            .try_forward_seq(&split_rows(x, self.d_model)?)?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+}
+
+/// Adds a vector per position to a sequence, either the fixed sinusoidal encodings of
+/// "Attention Is All You Need" or learned ones.
+pub struct PositionalEncoding {
+    table: Vec<Vec<Value>>,
+    d_model: usize,
+    // the table holds parameters, even while frozen
+    learned: bool,
+}
+
+impl PositionalEncoding {
+    /// `pe[t][2i] = sin(t / 10000^(2i / d))`, `pe[t][2i + 1] = cos(t / 10000^(2i / d))`.
+    pub fn sinusoidal(max_len: usize, d_model: usize) -> PositionalEncoding {
+        let table = (0..max_len)
+            .map(|t| {
+                (0..d_model)
+                    .map(|i| {
+                        let angle = t as f64 / 10000f64.powf((i - i % 2) as f64 / d_model as f64);
+                        Value::constant(if i % 2 == 0 { angle.sin() } else { angle.cos() })
+                    })
+                    .collect()
+            })
+            .collect();
+        PositionalEncoding {
+            table,
+            d_model,
+            learned: false,
+        }
+    }
+
+    pub fn learned(max_len: usize, d_model: usize) -> PositionalEncoding {
+        let mut rng = rand::rng();
+        let table = (0..max_len)
+            .map(|_| {
+                (0..d_model)
+                    .map(|_| Value::new(rng.random_range(-0.1..=0.1)))
+                    .collect()
+            })
+            .collect();
+        PositionalEncoding {
+            table,
+            d_model,
+            learned: true,
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// The encoding of position `t`.
+    pub fn encoding(&self, t: usize) -> Vec<f64> {
+        self.table[t].iter().map(|v| v.data()).collect()
+    }
+
+    pub fn forward_seq(&self, xs: &[Vec<Value>]) -> Vec<Vec<Value>> {
+        self.try_forward_seq(xs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_forward_seq(&self, xs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        check_rows(xs, self.d_model)?;
+        if xs.len() > self.table.len() {
+            return Err(Error::IndexOutOfRange {
+                index: xs.len() - 1,
+                len: self.table.len(),
+            });
+        }
+        Ok(xs
+            .iter()
+            .zip(self.table.iter())
+            .map(|(x, pe)| x.iter().zip(pe.iter()).map(|(x, p)| x + p).collect())
+            .collect())
+    }
+}
+
+impl Module for PositionalEncoding {
+    /// Empty for the sinusoidal encodings.
+    fn parameters(&self) -> Vec<Prev> {
+        if !self.learned {
+            return vec![];
+        }
+        self.table
+            .iter()
+            .flatten()
+            .map(|p| Prev(p.0.clone()))
+            .collect()
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        Ok(self
+            .try_forward_seq(&split_rows(x, self.d_model)?)?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+}
+
+/// Pre-norm transformer block: `x + attn(ln1(x))`, then `x + mlp(ln2(x))` on each position,
+/// where `mlp` has one ReLU hidden layer of size `d_ff`.
+pub struct TransformerBlock {
+    d_model: usize,
+    ln1: LayerNorm,
+    attn: MultiHeadAttention,
+    ln2: LayerNorm,
+    mlp: MLP,
+}
+
+impl TransformerBlock {
+    pub fn new(d_model: usize, n_heads: usize, d_ff: usize, causal: bool) -> TransformerBlock {
+        TransformerBlock {
+            d_model,
+            ln1: LayerNorm::new(d_model),
+            attn: MultiHeadAttention::new(d_model, n_heads, causal),
+            ln2: LayerNorm::new(d_model),
+            mlp: MLP::new(d_model, &[d_ff, d_model]),
+        }
+    }
+
+    pub fn attention(&self) -> &MultiHeadAttention {
+        &self.attn
+    }
+
+    pub fn forward_seq(&self, xs: &[Vec<Value>]) -> Vec<Vec<Value>> {
+        self.try_forward_seq(xs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_forward_seq(&self, xs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        check_rows(xs, self.d_model)?;
+        let normed = xs
+            .iter()
+            .map(|x| self.ln1.try_forward(x))
+            .collect::<Result<Vec<_>>>()?;
+        let attn = self.attn.try_forward_seq(&normed)?;
+        let mut out = Vec::with_capacity(xs.len());
+        for (x, a) in xs.iter().zip(attn.iter()) {
+            let h = x
+                .iter()
+                .zip(a.iter())
+                .map(|(x, a)| x + a)
+                .collect::<Vec<_>>();
+            let m = self.mlp.try_forward(&self.ln2.try_forward(&h)?)?;
+            out.push(h.iter().zip(m.iter()).map(|(h, m)| h + m).collect());
+        }
+        Ok(out)
+    }
+}
+
+impl Module for TransformerBlock {
+    fn train(&self, mode: bool) {
+        self.mlp.train(mode);
+    }
+
+    fn is_training(&self) -> bool {
+        self.mlp.is_training()
+    }
+
+    fn parameters(&self) -> Vec<Prev> {
+        let mut parameters = self.ln1.parameters();
+        parameters.append(&mut self.attn.parameters());
+        parameters.append(&mut self.ln2.parameters());
+        parameters.append(&mut self.mlp.parameters());
+        parameters
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        Ok(self
+            .try_forward_seq(&split_rows(x, self.d_model)?)?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_hook_scales_parameter_grads() {
+        let mlp = MLP::new(2, &[3, 1]);
+        let x = vec![Value::constant(0.5), Value::constant(-1.0)];
+
+        mlp.forward(&x)[0].backward();
+        let grads = mlp
+            .parameters()
+            .iter()
+            .map(|p| p.value().grad())
+            .collect::<Vec<_>>();
+
+        mlp.zero_grad();
+        mlp.register_hook(|g| 2.0 * g);
+        mlp.forward(&x)[0].backward();
+        for (p, g) in mlp.parameters().iter().zip(grads) {
+            assert_eq!(p.value().grad(), 2.0 * g);
+        }
+        mlp.clear_hooks();
+    }
+
+    #[test]
+    fn try_forward_and_load_parameters() {
+        let mlp = MLP::new(2, &[3, 1]);
+        let err = mlp.try_forward(&[Value::constant(1.0)]).unwrap_err();
+        assert_eq!(
+            err,
+            Error::InputSize {
+                expected: 2,
+                got: 1
+            }
+        );
+
+        let other = MLP::new(2, &[3, 1]);
+        other.load_parameters(&mlp.parameters_data()).unwrap();
+        let x = vec![Value::constant(0.5), Value::constant(-1.0)];
+        assert_eq!(other.forward(&x)[0].data(), mlp.forward(&x)[0].data());
+        assert!(other.load_parameters(&[1.0]).is_err());
+    }
+
+    #[test]
+    fn dropout_train_and_eval() {
+        let x = (0..1000).map(|_| Value::new(1.0)).collect::<Vec<_>>();
+        let dropout = Dropout::new_with_seed(0.25, 42);
+        let y = dropout.forward(&x);
+        let kept = y.iter().filter(|v| v.data() != 0.0).count();
+        assert!((700..800).contains(&kept), "kept {}", kept);
+        assert!(
+            y.iter()
+                .all(|v| v.data() == 0.0 || (v.data() - 4.0 / 3.0).abs() < 1e-12)
+        );
+
+        // dropped inputs get no gradient
+        for (x, y) in x.iter().zip(y.iter()) {
+            y.backward();
+            assert_eq!(x.grad() != 0.0, y.data() != 0.0);
+        }
+
+        // same seed, same mask
+        let again = Dropout::new_with_seed(0.25, 42).forward(&x);
+        assert!(
+            y.iter()
+                .zip(again.iter())
+                .all(|(a, b)| a.data() == b.data())
+        );
+
+        dropout.eval();
+        assert!(!dropout.is_training());
+        assert!(dropout.forward(&x).iter().all(|v| v.data() == 1.0));
+    }
+
+    #[test]
+    fn sequential_propagates_train_mode() {
+        let mut model = Sequential::new(vec![Box::new(Layer::new(2, 8, true))]);
+        model.push(Dropout::new_with_seed(0.5, 0));
+        model.push(Layer::new(8, 1, false));
+        assert_eq!(model.len(), 3);
+        assert_eq!(model.parameters().len(), 8 * 3 + 9);
+
+        let x = vec![Value::constant(0.5), Value::constant(-1.0)];
+        model.eval();
+        assert!(!model.is_training());
+        assert!(model.modules.iter().all(|m| !m.is_training()));
+        let a = model.forward(&x)[0].data();
+        assert_eq!(a, model.forward(&x)[0].data());
+
+        model.train(true);
+        assert!(model.modules.iter().all(|m| m.is_training()));
+        assert!(model.try_forward(&[Value::constant(1.0)]).is_err());
+    }
+
+    // d loss / d x by central differences, loss = sum_i w_i * out_i
+    fn numeric_grad<F: Fn(&[f64]) -> Vec<f64>>(f: F, x: &[f64], w: &[f64]) -> Vec<f64> {
+        let h = 1e-6;
+        let loss = |x: &[f64]| f(x).iter().zip(w.iter()).map(|(a, b)| a * b).sum::<f64>();
+        (0..x.len())
+            .map(|i| {
+                let (mut plus, mut minus) = (x.to_vec(), x.to_vec());
+                plus[i] += h;
+                minus[i] -= h;
+                (loss(&plus) - loss(&minus)) / (2.0 * h)
+            })
+            .collect()
+    }
+
+    fn weighted_sum(out: &[Value], w: &[f64]) -> Value {
+        let mut loss = Value::constant(0.0);
+        for (o, w) in out.iter().zip(w.iter()) {
+            loss = &loss + &(o * *w);
+        }
+        loss
+    }
+
+    #[test]
+    fn batch_norm_train_and_eval() {
+        let bn = BatchNorm1d::new(2);
+        let raw = [[1.0, -2.0], [2.0, 0.0], [4.0, 5.0]];
+        let xs = raw
+            .iter()
+            .map(|r| r.iter().map(|&v| Value::new(v)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert!(bn.try_forward(&xs[0]).is_err());
+        assert!(bn.try_forward_batch(&xs[..1]).is_err());
+
+        let ys = bn.forward_batch(&xs);
+        for j in 0..2 {
+            let mean = ys.iter().map(|y| y[j].data()).sum::<f64>() / 3.0;
+            let var = ys.iter().map(|y| y[j].data().powi(2)).sum::<f64>() / 3.0;
+            assert!(mean.abs() < 1e-9);
+            assert!((var - 1.0).abs() < 1e-4);
+        }
+        // mean 7/3, unbiased var 7/3 for the first feature
+        assert!((bn.running_mean()[0] - 0.1 * 7.0 / 3.0).abs() < 1e-12);
+        assert!((bn.running_var()[0] - (0.9 + 0.1 * 7.0 / 3.0)).abs() < 1e-12);
+
+        // gradient of the inputs through the batch statistics
+        let w = [0.3, -1.2, 0.7, 2.0, -0.4, 1.1];
+        let out = ys.iter().flatten().map(|v| v.clone()).collect::<Vec<_>>();
+        weighted_sum(&out, &w).backward();
+        let numeric = numeric_grad(
+            |x| {
+                let bn = BatchNorm1d::new(2);
+                let xs = x
+                    .chunks(2)
+                    .map(|r| r.iter().map(|&v| Value::new(v)).collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+                bn.forward_batch(&xs)
+                    .iter()
+                    .flatten()
+                    .map(|v| v.data())
+                    .collect()
+            },
+            &raw.concat(),
+            &w,
+        );
+        for (x, g) in xs.iter().flatten().zip(numeric) {
+            assert!((x.grad() - g).abs() < 1e-5, "{} != {}", x.grad(), g);
+        }
+
+        bn.eval();
+        let y = bn.forward(&vec![
+            Value::constant(bn.running_mean()[0]),
+            Value::constant(0.0),
+        ]);
+        assert!(y[0].data().abs() < 1e-12);
+        assert_eq!(bn.parameters().len(), 4);
+    }
+
+    #[test]
+    fn norm_layers_reject_invalid_options() {
+        let invalid = |r: Result<()>| matches!(r, Err(Error::InvalidArgument(_)));
+        assert!(invalid(LayerNorm::try_new(0).map(|_| ())));
+        assert!(invalid(
+            LayerNorm::try_new_with_options(3, 0.0, true).map(|_| ())
+        ));
+        assert!(LayerNorm::try_new_with_options(3, 1e-5, false).is_ok());
+
+        assert!(invalid(BatchNorm1d::try_new(0).map(|_| ())));
+        assert!(invalid(
+            BatchNorm1d::try_new_with_options(3, 0.1, -1e-5, true).map(|_| ())
+        ));
+        assert!(invalid(
+            BatchNorm1d::try_new_with_options(3, 1.5, 1e-5, true).map(|_| ())
+        ));
+        assert!(invalid(
+            BatchNorm1d::try_new_with_options(3, f64::NAN, 1e-5, true).map(|_| ())
+        ));
+        assert!(BatchNorm1d::try_new_with_options(3, 1.0, 1e-5, false).is_ok());
+    }
+
+    #[test]
+    fn layer_norm_gradients() {
+        let ln = LayerNorm::new(4);
+        ln.affine.gamma[1].set_data(2.0);
+        ln.affine.beta[2].set_data(-1.0);
+        let raw = [0.5, -1.5, 2.0, 3.0];
+        let x = raw.iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+        let y = ln.forward(&x);
+        let plain = LayerNorm::new_with_options(4, 1e-5, false).forward(&x);
+        let mean = plain.iter().map(|v| v.data()).sum::<f64>() / 4.0;
+        assert!(mean.abs() < 1e-9);
+        assert!((y[1].data() - 2.0 * plain[1].data()).abs() < 1e-12);
+
+        let w = [1.0, -0.5, 0.25, 2.0];
+        weighted_sum(&y, &w).backward();
+        let numeric = numeric_grad(
+            |x| {
+                let x = x.iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+                let scale = [1.0, 2.0, 1.0, 1.0];
+                let shift = [0.0, 0.0, -1.0, 0.0];
+                LayerNorm::new_with_options(4, 1e-5, false)
+                    .forward(&x)
+                    .iter()
+                    .enumerate()
+                    .map(|(j, v)| v.data() * scale[j] + shift[j])
+                    .collect()
+            },
+            &raw,
+            &w,
+        );
+        for (x, g) in x.iter().zip(numeric) {
+            assert!((x.grad() - g).abs() < 1e-5, "{} != {}", x.grad(), g);
+        }
+        assert!(ln.try_forward(&x[..3]).is_err());
+    }
+
+    #[test]
+    fn embedding_lookup_and_grads() {
+        let emb = Embedding::new_with_options(5, 3, Some(0), None);
+        assert_eq!(emb.parameters().len(), 15);
+        assert!(emb.lookup(0).iter().all(|v| v.data() == 0.0));
+
+        // index 2 twice and the padding index
+        let x = vec![2.0, 0.0, 2.0]
+            .into_iter()
+            .map(Value::constant)
+            .collect();
+        let out = emb.forward(&x);
+        assert_eq!(out.len(), 9);
+        assert_eq!(out[0].data(), emb.lookup(2)[0].data());
+
+        let mut loss = Value::constant(0.0);
+        for (i, v) in out.iter().enumerate() {
+            loss = &loss + &(v * (i + 1) as f64);
+        }
+        loss.backward();
+        assert_eq!(emb.lookup(2)[1].grad(), 2.0 + 8.0);
+        assert!(emb.lookup(0).iter().all(|v| v.grad() == 0.0));
+        assert!(emb.lookup(1).iter().all(|v| v.grad() == 0.0));
+
+        assert_eq!(
+            emb.try_lookup(5).unwrap_err(),
+            Error::IndexOutOfRange { index: 5, len: 5 }
+        );
+        assert!(emb.try_forward(&[Value::constant(1.5)]).is_err());
+    }
+
+    #[test]
+    fn embedding_max_norm() {
+        let emb = Embedding::new_with_options(2, 2, None, Some(1.0));
+        emb.weight[1][0].set_data(3.0);
+        emb.weight[1][1].set_data(4.0);
+        let row = emb.lookup(1);
+        assert!((row[0].data() - 0.6).abs() < 1e-6);
+        assert!((row[1].data() - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn embedding_rejects_invalid_options() {
+        let invalid = |r: Result<Embedding>| matches!(r, Err(Error::InvalidArgument(_)));
+        assert!(invalid(Embedding::try_new_with_options(
+            3,
+            2,
+            Some(3),
+            None
+        )));
+        assert!(invalid(Embedding::try_new_with_options(
+            3,
+            2,
+            None,
+            Some(0.0)
+        )));
+        assert!(invalid(Embedding::try_new_with_options(
+            3,
+            2,
+            None,
+            Some(-1.0)
+        )));
+        let emb = Embedding::try_new_with_options(3, 2, Some(2), Some(1.0)).unwrap();
+        assert_eq!(emb.padding_idx(), Some(2));
+        assert!(emb.lookup(2).iter().all(|v| !v.requires_grad()));
+    }
+
+    fn sequence(rows: &[[f64; 4]]) -> Vec<Vec<Value>> {
+        rows.iter()
+            .map(|r| r.iter().map(|&v| Value::new(v)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn causal_attention_does_not_look_ahead() {
+        let attn = MultiHeadAttention::new(4, 2, true);
+        let xs = sequence(&[
+            [0.1, -0.2, 0.3, 0.5],
+            [1.0, 0.0, -0.5, 0.2],
+            [-0.3, 0.8, 0.1, -1.0],
+        ]);
+        let weights = attn.attention_weights(&xs).unwrap();
+        assert_eq!(weights.len(), 2);
+        for head in weights.iter() {
+            assert_eq!(head[0], vec![1.0, 0.0, 0.0]);
+            assert_eq!(head[1][2], 0.0);
+            for row in head.iter() {
+                assert!((row.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+            }
+        }
+
+        let out = attn.forward_seq(&xs);
+        let mut loss = Value::constant(0.0);
+        for o in out[1].iter() {
+            loss = &loss + o;
+        }
+        loss.backward();
+        assert!(xs[2].iter().all(|x| x.grad() == 0.0));
+        assert!(xs[0].iter().any(|x| x.grad() != 0.0));
+        assert!(attn.try_forward(&xs[0][..3]).is_err());
+    }
+
+    #[test]
+    fn attention_grads_match_numeric() {
+        let block = TransformerBlock::new(4, 2, 8, false);
+        assert_eq!(
+            block.parameters().len(),
+            2 * 8 + 4 * 20 + (4 * 8 + 8) + (8 * 4 + 4)
+        );
+        let raw = [[0.5, -1.0, 0.2, 0.0], [0.3, 0.4, -0.6, 1.2]];
+        let loss = |block: &TransformerBlock| {
+            let out = block.forward_seq(&sequence(&raw));
+            let mut loss = Value::constant(0.0);
+            for (i, o) in out.iter().flatten().enumerate() {
+                loss = &loss + &(o * (i as f64 * 0.1 - 0.3));
+            }
+            loss
+        };
+
+        loss(&block).backward();
+        let h = 1e-6;
+        // the query, key and value projections of the attention
+        for p in block.attention().parameters()[..60].iter().step_by(7) {
+            let p = p.value();
+            let x = p.data();
+            p.set_data(x + h);
+            let plus = loss(&block).data();
+            p.set_data(x - h);
+            let minus = loss(&block).data();
+            p.set_data(x);
+            let numeric = (plus - minus) / (2.0 * h);
+            assert!(
+                (p.grad() - numeric).abs() < 1e-5,
+                "{} != {}",
+                p.grad(),
+                numeric
+            );
+        }
+    }
+
+    #[test]
+    fn positional_encoding() {
+        let pe = PositionalEncoding::sinusoidal(8, 4);
+        assert_eq!(pe.encoding(0), vec![0.0, 1.0, 0.0, 1.0]);
+        let e = pe.encoding(3);
+        assert!((e[0] - 3f64.sin()).abs() < 1e-12);
+        assert!((e[3] - (3.0 / 100.0f64).cos()).abs() < 1e-12);
+        assert!(pe.parameters().is_empty());
+        let learned = PositionalEncoding::learned(8, 4);
+        assert_eq!(learned.parameters().len(), 32);
+        // a frozen table is still made of parameters, so it can be unfrozen
+        learned.freeze();
+        assert_eq!(learned.parameters().len(), 32);
+        learned.unfreeze();
+        assert!(
+            learned
+                .parameters()
+                .iter()
+                .all(|p| p.value().requires_grad())
+        );
+        pe.unfreeze();
+        assert!(!pe.table[0][0].requires_grad());
+
+        let xs = sequence(&[[1.0; 4], [1.0; 4]]);
+        let out = pe.forward_seq(&xs);
+        assert_eq!(out[1][0].data(), 1.0 + 1f64.sin());
+        let long = sequence(&[[0.0; 4]; 9]);
+        assert!(pe.try_forward_seq(&long).is_err());
+    }
+
+    #[test]
+    fn named_parameters_and_freeze() {
+        let mlp = MLP::new(2, &[3, 1]);
+        let named = mlp.named_parameters();
+        assert_eq!(named.len(), mlp.parameters().len());
+        assert_eq!(named[0].0, "layers.0.0.w.0");
+        assert_eq!(named[2].0, "layers.0.0.b");
+        assert_eq!(named.last().unwrap().0, "layers.1.0.b");
+        assert!(
+            named
+                .iter()
+                .zip(mlp.parameters())
+                .all(|((_, a), b)| *a == b)
+        );
+
+        let model = Sequential::new(vec![Box::new(LayerNorm::new(2)), Box::new(mlp)]);
+        let names = model
+            .named_parameters()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect::<Vec<_>>();
+        assert_eq!(names[1], "0.gamma.1");
+        assert_eq!(names[4], "1.layers.0.0.w.0");
+
+        // fine-tune the last layer only
+        model.freeze();
+        model.get(1).unwrap().unfreeze();
+        let x = vec![Value::constant(0.5), Value::constant(-1.0)];
+        model.forward(&x)[0].backward();
+        for (name, p) in model.named_parameters() {
+            assert_eq!(p.value().requires_grad(), name.starts_with('1'));
+            if name.starts_with('0') {
+                assert_eq!(p.value().grad(), 0.0);
+            }
+        }
+
+        let emb = Embedding::new_with_options(3, 2, Some(1), None);
+        emb.freeze();
+        emb.unfreeze();
+        assert!(emb.lookup(1).iter().all(|v| !v.requires_grad()));
+        assert!(emb.lookup(0).iter().all(|v| v.requires_grad()));
     }
 }
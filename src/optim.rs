@@ -0,0 +1,391 @@
+use crate::Prev;
+use crate::nn::Module;
+use std::collections::HashMap;
+
+/// Global L2 norm of the gradients of `params`.
+pub fn grad_norm(params: &[Prev]) -> f64 {
+    params
+        .iter()
+        .map(|p| p.value().grad().powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Rescales the gradients of `params` so that their global L2 norm is at most `|max_norm|`,
+/// a `max_norm` of 0 zeroes them.
+///
+/// Returns the norm before clipping. A non-finite norm or a NaN `max_norm` is returned as is
+/// and leaves the gradients untouched.
+pub fn clip_grad_norm(params: &[Prev], max_norm: f64) -> f64 {
+    let total_norm = grad_norm(params);
+    let max_norm = max_norm.abs();
+    if total_norm.is_finite() && total_norm > max_norm {
+        let scale = max_norm / (total_norm + 1e-6);
+        for p in params.iter() {
+            let value = p.value();
+            value.set_grad(value.grad() * scale);
+        }
+    }
+    total_norm
+}
+
+/// Clamps every gradient of `params` into `[-|clip_value|, |clip_value|]`.
+///
+/// Returns the global L2 norm before clipping. A NaN `clip_value` leaves the gradients
+/// untouched.
+pub fn clip_grad_value(params: &[Prev], clip_value: f64) -> f64 {
+    let total_norm = grad_norm(params);
+    if clip_value.is_nan() {
+        return total_norm;
+    }
+    let clip_value = clip_value.abs();
+    for p in params.iter() {
+        let value = p.value();
+        value.set_grad(value.grad().clamp(-clip_value, clip_value));
+    }
+    total_norm
+}
+
+/// Parameters that share a learning rate and a weight decay.
+pub struct ParamGroup {
+    params: Vec<Prev>,
+    pub lr: f64,
+    /// L2 penalty, `weight_decay * p` is added to the gradient of each parameter.
+    pub weight_decay: f64,
+}
+
+impl ParamGroup {
+    pub fn new(params: Vec<Prev>, lr: f64) -> ParamGroup {
+        ParamGroup::new_with_weight_decay(params, lr, 0.0)
+    }
+
+    pub fn new_with_weight_decay(params: Vec<Prev>, lr: f64, weight_decay: f64) -> ParamGroup {
+        ParamGroup {
+            params,
+            lr,
+            weight_decay,
+        }
+    }
+
+    /// The parameters of `module` under the path `prefix`, see `Module::named_parameters`,
+    /// e.g. `layers.2` for the third layer of an `MLP`. Whole segments are matched, so
+    /// `layers.1` does not select `layers.10`, and a trailing `.` is ignored.
+    pub fn from_prefix<M: Module + ?Sized>(module: &M, prefix: &str, lr: f64) -> ParamGroup {
+        let prefix = prefix.trim_end_matches('.');
+        let params = module
+            .named_parameters()
+            .into_iter()
+            .filter(|(name, _)| {
+                prefix.is_empty()
+                    || name
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+            .map(|(_, p)| p)
+            .collect();
+        ParamGroup::new(params, lr)
+    }
+
+    pub fn params(&self) -> &[Prev] {
+        &self.params
+    }
+}
+
+/// Updates the parameters of its groups from their gradients.
+///
+/// Frozen parameters (that do not require grad) are skipped, so a group can hold a whole
+/// model while only some of its layers are trained.
+pub trait Optimizer {
+    fn param_groups(&self) -> &[ParamGroup];
+
+    /// To change the learning rate or the weight decay of a group during training, or to
+    /// replace a group. The state of the optimizers is kept per parameter, so parameters that
+    /// were not optimized before start from a zero state.
+    fn param_groups_mut(&mut self) -> &mut [ParamGroup];
+
+    fn step(&mut self);
+
+    fn zero_grad(&self) {
+        for group in self.param_groups().iter() {
+            for p in group.params.iter() {
+                p.value().set_grad(0.0);
+            }
+        }
+    }
+}
+
+// gradient of a parameter with the weight decay of its group, `None` when it is frozen
+fn decayed_grad(p: &Prev, weight_decay: f64) -> Option<f64> {
+    let value = p.value();
+    value
+        .requires_grad()
+        .then(|| value.grad() + weight_decay * value.data())
+}
+
+/// Stochastic gradient descent with momentum, `v = momentum * v + g`, `p -= lr * v`.
+pub struct SGD {
+    groups: Vec<ParamGroup>,
+    momentum: f64,
+    velocity: HashMap<Prev, f64>,
+}
+
+impl SGD {
+    pub fn new(groups: Vec<ParamGroup>) -> SGD {
+        SGD::new_with_momentum(groups, 0.0)
+    }
+
+    pub fn new_with_momentum(groups: Vec<ParamGroup>, momentum: f64) -> SGD {
+        SGD {
+            groups,
+            momentum,
+            velocity: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for SGD {
+    fn param_groups(&self) -> &[ParamGroup] {
+        &self.groups
+    }
+
+    fn param_groups_mut(&mut self) -> &mut [ParamGroup] {
+        &mut self.groups
+    }
+
+    fn step(&mut self) {
+        for group in self.groups.iter() {
+            for p in group.params.iter() {
+                let Some(g) = decayed_grad(p, group.weight_decay) else {
+                    continue;
+                };
+                let v = self.velocity.entry(p.clone()).or_insert(0.0);
+                *v = self.momentum * *v + g;
+                p.value().add_data(-group.lr * *v);
+            }
+        }
+    }
+}
+
+/// Adam, with the bias corrected first and second moment estimates of the gradients.
+pub struct Adam {
+    groups: Vec<ParamGroup>,
+    beta1: f64,
+    beta2: f64,
+    eps: f64,
+    state: HashMap<Prev, AdamState>,
+}
+
+// moments of one parameter and the number of steps it took, for the bias correction
+#[derive(Default)]
+struct AdamState {
+    t: i32,
+    m: f64,
+    v: f64,
+}
+
+impl Adam {
+    pub fn new(groups: Vec<ParamGroup>) -> Adam {
+        Adam::new_with_options(groups, 0.9, 0.999, 1e-8)
+    }
+
+    pub fn new_with_options(groups: Vec<ParamGroup>, beta1: f64, beta2: f64, eps: f64) -> Adam {
+        Adam {
+            groups,
+            beta1,
+            beta2,
+            eps,
+            state: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn param_groups(&self) -> &[ParamGroup] {
+        &self.groups
+    }
+
+    fn param_groups_mut(&mut self) -> &mut [ParamGroup] {
+        &mut self.groups
+    }
+
+    fn step(&mut self) {
+        for group in self.groups.iter() {
+            for p in group.params.iter() {
+                let Some(g) = decayed_grad(p, group.weight_decay) else {
+                    continue;
+                };
+                let state = self.state.entry(p.clone()).or_default();
+                state.t += 1;
+                state.m = self.beta1 * state.m + (1.0 - self.beta1) * g;
+                state.v = self.beta2 * state.v + (1.0 - self.beta2) * g * g;
+                let c1 = 1.0 - self.beta1.powi(state.t);
+                let c2 = 1.0 - self.beta2.powi(state.t);
+                let step = group.lr * (state.m / c1) / ((state.v / c2).sqrt() + self.eps);
+                p.value().add_data(-step);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn params(data: &[f64], grads: &[f64]) -> Vec<Prev> {
+        data.iter()
+            .zip(grads.iter())
+            .map(|(&d, &g)| {
+                let v = Value::new(d);
+                v.set_grad(g);
+                Prev(v.0.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clip_grad_norm_test() {
+        let ps = params(&[0.0; 2], &[3.0, -4.0]);
+        assert_eq!(clip_grad_norm(&ps, 10.0), 5.0);
+        assert_eq!(ps[0].value().grad(), 3.0);
+
+        assert_eq!(clip_grad_norm(&ps, 1.0), 5.0);
+        assert!((grad_norm(&ps) - 1.0).abs() < 1e-6);
+        assert!((ps[0].value().grad() / ps[1].value().grad() + 0.75).abs() < 1e-12);
+
+        // the sign of the bound is ignored, 0 zeroes the grads and NaN does not clip
+        let ps = params(&[0.0; 2], &[3.0, -4.0]);
+        clip_grad_norm(&ps, -1.0);
+        assert!(ps[0].value().grad() > 0.0 && ps[1].value().grad() < 0.0);
+        assert!((grad_norm(&ps) - 1.0).abs() < 1e-6);
+        clip_grad_norm(&ps, f64::NAN);
+        assert!((grad_norm(&ps) - 1.0).abs() < 1e-6);
+        clip_grad_norm(&ps, 0.0);
+        assert_eq!(grad_norm(&ps), 0.0);
+    }
+
+    #[test]
+    fn clip_grad_value_test() {
+        let ps = params(&[0.0; 3], &[3.0, -4.0, 0.5]);
+        assert_eq!(clip_grad_value(&ps, 1.0), (25.25f64).sqrt());
+        let grads = ps.iter().map(|p| p.value().grad()).collect::<Vec<_>>();
+        assert_eq!(grads, vec![1.0, -1.0, 0.5]);
+
+        // the sign of the bound is ignored and NaN does not clip
+        let ps = params(&[0.0; 3], &[3.0, -4.0, 0.5]);
+        clip_grad_value(&ps, -2.0);
+        clip_grad_value(&ps, f64::NAN);
+        let grads = ps.iter().map(|p| p.value().grad()).collect::<Vec<_>>();
+        assert_eq!(grads, vec![2.0, -2.0, 0.5]);
+    }
+
+    #[test]
+    fn sgd_groups_and_frozen_params() {
+        let ps = params(&[1.0, 2.0, 3.0], &[0.5; 3]);
+        let (a, b, frozen) = (&ps[0], &ps[1], &ps[2]);
+        frozen.value().set_requires_grad(false);
+        let mut sgd = SGD::new_with_momentum(
+            vec![
+                ParamGroup::new(vec![a.clone(), frozen.clone()], 0.1),
+                ParamGroup::new_with_weight_decay(vec![b.clone()], 0.01, 0.5),
+            ],
+            0.9,
+        );
+        sgd.step();
+        assert!((a.value().data() - 0.95).abs() < 1e-12);
+        assert!((b.value().data() - (2.0 - 0.01 * 1.5)).abs() < 1e-12);
+        assert_eq!(frozen.value().data(), 3.0);
+
+        // the velocity keeps 0.9 of the previous step
+        sgd.step();
+        assert!((a.value().data() - (0.95 - 0.1 * (0.9 * 0.5 + 0.5))).abs() < 1e-12);
+
+        sgd.param_groups_mut()[0].lr = 0.0;
+        sgd.zero_grad();
+        assert_eq!(a.value().grad(), 0.0);
+        assert_eq!(sgd.param_groups()[0].lr, 0.0);
+    }
+
+    #[test]
+    fn adam_first_step() {
+        // the first step of Adam moves each parameter by about lr, whatever the grad
+        let ps = params(&[1.0, 1.0], &[100.0, -0.001]);
+        let (a, b) = (&ps[0], &ps[1]);
+        let mut adam = Adam::new(vec![ParamGroup::new(vec![a.clone(), b.clone()], 0.1)]);
+        adam.step();
+        assert!((a.value().data() - 0.9).abs() < 1e-6);
+        assert!((b.value().data() - 1.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn from_prefix_matches_whole_segments() {
+        let mlp = crate::MLP::new(1, &[1; 11]);
+        let names = |prefix: &str| {
+            let group = ParamGroup::from_prefix(&mlp, prefix, 0.1);
+            let named = mlp.named_parameters();
+            group
+                .params()
+                .iter()
+                .map(|p| {
+                    let (name, _) = named.iter().find(|(_, q)| q == p).unwrap();
+                    name.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("layers.1"), vec!["layers.1.0.w.0", "layers.1.0.b"]);
+        assert_eq!(names("layers.1."), names("layers.1"));
+        assert_eq!(names("layers.10.0.b"), vec!["layers.10.0.b"]);
+        assert_eq!(names("layers.1.0.w.0."), vec!["layers.1.0.w.0"]);
+        assert!(names("layer").is_empty());
+        assert_eq!(names("").len(), mlp.parameters().len());
+    }
+
+    #[test]
+    fn replaced_groups_start_from_a_zero_state() {
+        let ps = params(&[1.0; 4], &[1.0; 4]);
+        let mut adam = Adam::new(vec![ParamGroup::new(vec![ps[0].clone()], 0.1)]);
+        let mut sgd = SGD::new_with_momentum(vec![ParamGroup::new(vec![ps[1].clone()], 0.1)], 0.9);
+        for _ in 0..3 {
+            adam.step();
+            sgd.step();
+        }
+
+        // the new parameters take the slots of the old ones but none of their state
+        adam.param_groups_mut()[0] = ParamGroup::new(vec![ps[2].clone()], 0.1);
+        sgd.param_groups_mut()[0] = ParamGroup::new(vec![ps[3].clone()], 0.1);
+        adam.step();
+        sgd.step();
+        assert!((ps[2].value().data() - 0.9).abs() < 1e-6);
+        assert!((ps[3].value().data() - 0.9).abs() < 1e-12);
+
+        // and putting an old parameter back resumes its state
+        sgd.param_groups_mut()[0] = ParamGroup::new(vec![ps[1].clone()], 0.1);
+        let before = ps[1].value().data();
+        sgd.step();
+        let velocity = 1.0 + 0.9 * (1.0 + 0.9 * (1.0 + 0.9));
+        assert!((before - ps[1].value().data() - 0.1 * velocity).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fine_tune_last_layer() {
+        let mlp = crate::MLP::new(2, &[4, 1]);
+        mlp.freeze();
+        mlp.layers()[1].unfreeze();
+        let before = mlp.parameters_data();
+        let group = ParamGroup::from_prefix(&mlp, "layers.1.", 0.1);
+        assert_eq!(group.params().len(), 5);
+        let mut sgd = SGD::new(vec![group, ParamGroup::from_prefix(&mlp, "layers.0", 0.1)]);
+
+        let x = vec![Value::constant(0.5), Value::constant(-1.0)];
+        for _ in 0..5 {
+            let y = &mlp.forward(&x)[0];
+            let loss = (y - 1.0).pow(2.0);
+            sgd.zero_grad();
+            loss.backward();
+            sgd.step();
+        }
+        let after = mlp.parameters_data();
+        assert_eq!(before[..12], after[..12]);
+        assert_ne!(before[12..], after[12..]);
+    }
+}
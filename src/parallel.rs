@@ -0,0 +1,139 @@
+use crate::Value;
+use crate::anomaly;
+use crate::nn::Module;
+use std::thread;
+
+/// Data-parallel backward pass.
+///
+/// `batch` is split into `n_threads` contiguous chunks. Every thread builds the losses of its
+/// chunk with `loss_fn`, scales them by `1 / batch.len()` and calls `backward`, so the gradients
+/// of the shared parameters of `model` end up holding the gradient of the mean loss.
+/// Returns the mean loss over the batch.
+///
+/// Gradients are added into the parameters, call `model.zero_grad()` before if needed.
+/// The workers run in the anomaly detection mode of the calling thread.
+pub fn data_parallel_backward<M, T, F>(model: &M, batch: &[T], n_threads: usize, loss_fn: F) -> f64
+where
+    M: Module + Sync,
+    T: Sync,
+    F: Fn(&M, &T) -> Value + Sync,
+{
+    if batch.is_empty() {
+        return 0.0;
+    }
+    let n_threads = n_threads.clamp(1, batch.len());
+    let chunk_size = batch.len().div_ceil(n_threads);
+    let scale = 1.0 / batch.len() as f64;
+    let loss_fn = &loss_fn;
+    let detect_anomaly = anomaly::is_anomaly_detection_enabled();
+
+    thread::scope(|s| {
+        let handles = batch
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    anomaly::set_detect_anomaly(detect_anomaly);
+                    let mut loss = Value::new(0.0);
+                    for sample in chunk {
+                        loss = &loss + &loss_fn(model, sample);
+                    }
+                    let loss = &loss * scale;
+                    loss.backward();
+                    loss.data()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("worker thread panicked"))
+            .sum()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MLP, MakeMoonDataset};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn value_is_send_sync() {
+        assert_send_sync::<Value>();
+        assert_send_sync::<MLP>();
+    }
+
+    #[test]
+    fn parallel_grad_matches_serial() {
+        let mlp = MLP::new(2, &[4, 1]);
+        let dataset = MakeMoonDataset::new(20);
+        let batch = (0..dataset.len())
+            .map(|i| dataset.get(i))
+            .collect::<Vec<_>>();
+        let loss_fn = |mlp: &MLP, (x, y): &(Vec<Value>, f64)| {
+            let out = &mlp.forward(x)[0] - *y;
+            &out * &out
+        };
+
+        mlp.zero_grad();
+        let serial_loss = data_parallel_backward(&mlp, &batch, 1, loss_fn);
+        let serial_grad = mlp
+            .parameters()
+            .iter()
+            .map(|p| p.value().grad())
+            .collect::<Vec<_>>();
+
+        mlp.zero_grad();
+        let parallel_loss = data_parallel_backward(&mlp, &batch, 4, loss_fn);
+        assert!((serial_loss - parallel_loss).abs() < 1e-9);
+        for (p, g) in mlp.parameters().iter().zip(serial_grad) {
+            assert!((p.value().grad() - g).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn hooks_see_every_worker_contribution() {
+        let mlp = MLP::new(2, &[4, 1]);
+        let dataset = MakeMoonDataset::new(40);
+        let batch = (0..dataset.len())
+            .map(|i| dataset.get(i))
+            .collect::<Vec<_>>();
+        let loss_fn = |mlp: &MLP, (x, y): &(Vec<Value>, f64)| {
+            let out = &mlp.forward(x)[0] - *y;
+            &out * &out
+        };
+
+        mlp.zero_grad();
+        data_parallel_backward(&mlp, &batch, 1, loss_fn);
+        let serial_grad = mlp
+            .parameters()
+            .iter()
+            .map(|p| p.value().grad())
+            .collect::<Vec<_>>();
+
+        mlp.zero_grad();
+        mlp.register_hook(|g| 2.0 * g);
+        data_parallel_backward(&mlp, &batch, 8, loss_fn);
+        mlp.clear_hooks();
+        for (p, g) in mlp.parameters().iter().zip(serial_grad) {
+            assert!((p.value().grad() - 2.0 * g).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn workers_inherit_anomaly_detection() {
+        let mlp = MLP::new(2, &[4, 1]);
+        let batch = vec![0.5, -1.0, 2.0, 0.0];
+        let loss_fn = |mlp: &MLP, x: &f64| {
+            let out = &mlp.forward(&vec![Value::constant(*x), Value::constant(1.0)])[0];
+            // infinite for the sample 0.0
+            &(out * 0.0) + &Value::constant(*x).ln()
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            anomaly::detect_anomaly(|| data_parallel_backward(&mlp, &batch, 2, loss_fn))
+        }));
+        assert!(result.is_err());
+        assert!(!data_parallel_backward(&mlp, &batch, 2, loss_fn).is_finite());
+    }
+}
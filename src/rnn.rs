@@ -0,0 +1,372 @@
+//! Recurrent cells and backpropagation through time.
+//!
+//! A cell maps an input and a state to the next state, the graph of a whole sequence is built
+//! by `unroll`, so a single `backward` on a loss over the outputs goes through every step.
+
+use crate::error::{Error, Result};
+use crate::nn::{Layer, Module};
+use crate::{Prev, Value};
+
+/// A `Module` that is applied once per time step.
+///
+/// As a `Module`, `forward` takes the input followed by the state and returns the next state.
+pub trait RecurrentCell: Module {
+    fn input_size(&self) -> usize;
+
+    fn hidden_size(&self) -> usize;
+
+    /// Size of the state, the hidden state followed by the cell state for `LSTMCell`.
+    fn state_size(&self) -> usize {
+        self.hidden_size()
+    }
+
+    /// All zeros.
+    fn init_state(&self) -> Vec<Value> {
+        (0..self.state_size())
+            .map(|_| Value::constant(0.0))
+            .collect()
+    }
+
+    /// Next state from the input and the current state.
+    fn step(&self, x: &[Value], state: &[Value]) -> Result<Vec<Value>>;
+
+    /// The hidden state, what the cell outputs at each step.
+    fn output(&self, state: &[Value]) -> Vec<Value> {
+        state[..self.hidden_size()]
+            .iter()
+            .map(|v| v.clone())
+            .collect()
+    }
+}
+
+/// Runs `cell` over the sequence `xs`, starting from `state` or from `init_state`.
+///
+/// Returns the output of each step and the final state.
+pub fn unroll<C: RecurrentCell + ?Sized>(
+    cell: &C,
+    xs: &[Vec<Value>],
+    state: Option<Vec<Value>>,
+) -> Result<(Vec<Vec<Value>>, Vec<Value>)> {
+    let mut state = state.unwrap_or_else(|| cell.init_state());
+    if state.len() != cell.state_size() {
+        return Err(Error::InvalidArgument(format!(
+            "state of size {} for a cell with a state of size {}",
+            state.len(),
+            cell.state_size()
+        )));
+    }
+    let mut outputs = Vec::with_capacity(xs.len());
+    for x in xs.iter() {
+        state = cell.step(x, &state)?;
+        outputs.push(cell.output(&state));
+    }
+    Ok((outputs, state))
+}
+
+fn check_step(input_size: usize, state_size: usize, x: &[Value], state: &[Value]) -> Result<()> {
+    if x.len() != input_size {
+        return Err(Error::InputSize {
+            expected: input_size,
+            got: x.len(),
+        });
+    }
+    if state.len() != state_size {
+        return Err(Error::InputSize {
+            expected: state_size,
+            got: state.len(),
+        });
+    }
+    Ok(())
+}
+
+fn concat(a: &[Value], b: &[Value]) -> Vec<Value> {
+    a.iter().chain(b.iter()).map(|v| v.clone()).collect()
+}
+
+// forward of a cell as a `Module`: the input and the state in one slice
+fn forward_cell<C: RecurrentCell>(cell: &C, x: &[Value]) -> Result<Vec<Value>> {
+    let n = cell.input_size();
+    if x.len() != n + cell.state_size() {
+        return Err(Error::InputSize {
+            expected: n + cell.state_size(),
+            got: x.len(),
+        });
+    }
+    cell.step(&x[..n], &x[n..])
+}
+
+/// Elman RNN, `h' = tanh(W [x, h] + b)`.
+pub struct RNNCell {
+    input_size: usize,
+    hidden_size: usize,
+    linear: Layer,
+}
+
+impl RNNCell {
+    pub fn new(input_size: usize, hidden_size: usize) -> RNNCell {
+        RNNCell {
+            input_size,
+            hidden_size,
+            linear: Layer::new(input_size + hidden_size, hidden_size, false),
+        }
+    }
+}
+
+impl RecurrentCell for RNNCell {
+    fn input_size(&self) -> usize {
+        self.input_size
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    fn step(&self, x: &[Value], state: &[Value]) -> Result<Vec<Value>> {
+        check_step(self.input_size, self.hidden_size, x, state)?;
+        let pre = self.linear.try_forward(&concat(x, state))?;
+        Ok(pre.iter().map(|v| v.tanh()).collect())
+    }
+}
+
+impl Module for RNNCell {
+    fn parameters(&self) -> Vec<Prev> {
+        self.linear.parameters()
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        forward_cell(self, x)
+    }
+}
+
+/// Gated recurrent unit.
+///
+/// `r = σ(W_r [x, h])`, `z = σ(W_z [x, h])`, `n = tanh(W_n x + r * (U_n h))`,
+/// `h' = (1 - z) * n + z * h`.
+pub struct GRUCell {
+    input_size: usize,
+    hidden_size: usize,
+    reset: Layer,
+    update: Layer,
+    new_x: Layer,
+    new_h: Layer,
+}
+
+impl GRUCell {
+    pub fn new(input_size: usize, hidden_size: usize) -> GRUCell {
+        GRUCell {
+            input_size,
+            hidden_size,
+            reset: Layer::new(input_size + hidden_size, hidden_size, false),
+            update: Layer::new(input_size + hidden_size, hidden_size, false),
+            new_x: Layer::new(input_size, hidden_size, false),
+            new_h: Layer::new(hidden_size, hidden_size, false),
+        }
+    }
+}
+
+impl RecurrentCell for GRUCell {
+    fn input_size(&self) -> usize {
+        self.input_size
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    fn step(&self, x: &[Value], state: &[Value]) -> Result<Vec<Value>> {
+        check_step(self.input_size, self.hidden_size, x, state)?;
+        let xh = concat(x, state);
+        let r = self.reset.try_forward(&xh)?;
+        let z = self.update.try_forward(&xh)?;
+        let nx = self.new_x.try_forward(x)?;
+        let nh = self.new_h.try_forward(state)?;
+        Ok((0..self.hidden_size)
+            .map(|i| {
+                let (r, z) = (r[i].sigmoid(), z[i].sigmoid());
+                let n = (&nx[i] + &(&r * &nh[i])).tanh();
+                // (1 - z) * n + z * h = n + z * (h - n)
+                &n + &(&z * &(&state[i] - &n))
+            })
+            .collect())
+    }
+}
+
+impl Module for GRUCell {
+    fn parameters(&self) -> Vec<Prev> {
+        let mut parameters = vec![];
+        for layer in [&self.reset, &self.update, &self.new_x, &self.new_h] {
+            parameters.append(&mut layer.parameters());
+        }
+        parameters
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        forward_cell(self, x)
+    }
+}
+
+/// Long short-term memory cell, the state is the hidden state `h` followed by the cell state `c`.
+///
+/// `i, f, o = σ(W_{i,f,o} [x, h])`, `g = tanh(W_g [x, h])`, `c' = f * c + i * g`,
+/// `h' = o * tanh(c')`.
+pub struct LSTMCell {
+    input_size: usize,
+    hidden_size: usize,
+    input_gate: Layer,
+    forget_gate: Layer,
+    cell_gate: Layer,
+    output_gate: Layer,
+}
+
+impl LSTMCell {
+    pub fn new(input_size: usize, hidden_size: usize) -> LSTMCell {
+        let gate = || Layer::new(input_size + hidden_size, hidden_size, false);
+        LSTMCell {
+            input_size,
+            hidden_size,
+            input_gate: gate(),
+            forget_gate: gate(),
+            cell_gate: gate(),
+            output_gate: gate(),
+        }
+    }
+
+    /// The cell state part of `state`.
+    pub fn cell_state(&self, state: &[Value]) -> Vec<Value> {
+        state[self.hidden_size..]
+            .iter()
+            .map(|v| v.clone())
+            .collect()
+    }
+}
+
+impl RecurrentCell for LSTMCell {
+    fn input_size(&self) -> usize {
+        self.input_size
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    fn state_size(&self) -> usize {
+        2 * self.hidden_size
+    }
+
+    fn step(&self, x: &[Value], state: &[Value]) -> Result<Vec<Value>> {
+        check_step(self.input_size, self.state_size(), x, state)?;
+        let (h, c) = state.split_at(self.hidden_size);
+        let xh = concat(x, h);
+        let i = self.input_gate.try_forward(&xh)?;
+        let f = self.forget_gate.try_forward(&xh)?;
+        let g = self.cell_gate.try_forward(&xh)?;
+        let o = self.output_gate.try_forward(&xh)?;
+
+        let c_next = (0..self.hidden_size)
+            .map(|k| &(&f[k].sigmoid() * &c[k]) + &(&i[k].sigmoid() * &g[k].tanh()))
+            .collect::<Vec<_>>();
+        let h_next = (0..self.hidden_size)
+            .map(|k| &o[k].sigmoid() * &c_next[k].tanh())
+            .collect::<Vec<_>>();
+        Ok(concat(&h_next, &c_next))
+    }
+}
+
+impl Module for LSTMCell {
+    fn parameters(&self) -> Vec<Prev> {
+        let mut parameters = vec![];
+        for layer in [
+            &self.input_gate,
+            &self.forget_gate,
+            &self.cell_gate,
+            &self.output_gate,
+        ] {
+            parameters.append(&mut layer.parameters());
+        }
+        parameters
+    }
+
+    fn try_forward(&self, x: &[Value]) -> Result<Vec<Value>> {
+        forward_cell(self, x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sum of the outputs of every step over a fixed sequence
+    fn sequence_loss<C: RecurrentCell>(cell: &C) -> Value {
+        let xs = [[0.5, -1.0], [1.5, 0.2], [-0.3, 0.8]]
+            .iter()
+            .map(|x| x.iter().map(|&v| Value::constant(v)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let (outputs, _) = unroll(cell, &xs, None).unwrap();
+        let mut loss = Value::constant(0.0);
+        for (t, out) in outputs.iter().enumerate() {
+            for o in out.iter() {
+                loss = &loss + &(o * (t + 1) as f64);
+            }
+        }
+        loss
+    }
+
+    // backpropagation through time against central differences on every parameter
+    fn check_bptt<C: RecurrentCell>(cell: &C) {
+        cell.zero_grad();
+        sequence_loss(cell).backward();
+        let h = 1e-6;
+        for p in cell.parameters().iter() {
+            let p = p.value();
+            let x = p.data();
+            p.set_data(x + h);
+            let plus = sequence_loss(cell).data();
+            p.set_data(x - h);
+            let minus = sequence_loss(cell).data();
+            p.set_data(x);
+            let numeric = (plus - minus) / (2.0 * h);
+            assert!(
+                (p.grad() - numeric).abs() < 1e-5,
+                "{} != {}",
+                p.grad(),
+                numeric
+            );
+        }
+    }
+
+    #[test]
+    fn cells_bptt_matches_numeric_grad() {
+        check_bptt(&RNNCell::new(2, 3));
+        check_bptt(&GRUCell::new(2, 3));
+        check_bptt(&LSTMCell::new(2, 3));
+    }
+
+    #[test]
+    fn unroll_shapes_and_errors() {
+        let lstm = LSTMCell::new(2, 4);
+        assert_eq!(lstm.parameters().len(), 4 * 4 * (2 + 4 + 1));
+        let xs = (0..5)
+            .map(|_| vec![Value::constant(1.0), Value::constant(0.0)])
+            .collect::<Vec<_>>();
+        let (outputs, state) = unroll(&lstm, &xs, None).unwrap();
+        assert_eq!(outputs.len(), 5);
+        assert!(outputs.iter().all(|o| o.len() == 4));
+        assert_eq!(state.len(), 8);
+        assert_eq!(outputs[4][0].data(), state[0].data());
+        assert_eq!(lstm.cell_state(&state).len(), 4);
+
+        // as a module: input followed by the state
+        let input = concat(&xs[0], &lstm.init_state());
+        assert_eq!(lstm.forward(&input).len(), 8);
+
+        assert!(unroll(&lstm, &xs, Some(vec![Value::constant(0.0)])).is_err());
+        let gru = GRUCell::new(3, 2);
+        assert_eq!(
+            unroll(&gru, &xs, None).unwrap_err(),
+            Error::InputSize {
+                expected: 3,
+                got: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Wengert-list (tape) autograd engine.
+//!
+//! `Value` allocates one `Arc` node, a parent set and a boxed closure per op, and has to
+//! topologically sort the graph on every `backward`. A `Tape` instead appends nodes to one
+//! contiguous `Vec`: a `Var` is only an index into it, ops are an enum, and because nodes are
+//! pushed in evaluation order `backward` is a single reverse sweep over the tape.
+//!
+//! Parameters still live in `Value`s: load them with `Tape::load`, build the graph on the tape
+//! and copy the gradients back with `Grads::accumulate`.
+
+use crate::Prev;
+use std::cell::RefCell;
+use std::ops::{Add, Div, Index, Mul, Sub};
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Leaf,
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    Div(usize, usize),
+    Pow(usize, usize),
+    PowConst(usize, f64),
+    Relu(usize),
+}
+
+#[derive(Debug)]
+struct Node {
+    op: Op,
+    data: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Tape {
+    nodes: RefCell<Vec<Node>>,
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Tape {
+        Tape {
+            nodes: RefCell::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    pub fn var(&self, data: f64) -> Var<'_> {
+        self.push(Op::Leaf, data)
+    }
+
+    /// Pushes one leaf per parameter, in the same order as `params`.
+    pub fn load(&self, params: &[Prev]) -> Vec<Var<'_>> {
+        params.iter().map(|p| self.var(p.value().data())).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every node but keeps the allocation, so the tape can be reused for the next step.
+    pub fn clear(&mut self) {
+        self.nodes.get_mut().clear();
+    }
+
+    fn push(&self, op: Op, data: f64) -> Var<'_> {
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.push(Node { op, data });
+        Var {
+            tape: self,
+            idx: nodes.len() - 1,
+        }
+    }
+
+    fn data(&self, idx: usize) -> f64 {
+        self.nodes.borrow()[idx].data
+    }
+}
+
+/// A node on a `Tape`.
+#[derive(Debug, Clone, Copy)]
+pub struct Var<'t> {
+    tape: &'t Tape,
+    idx: usize,
+}
+
+impl<'t> Var<'t> {
+    pub fn data(&self) -> f64 {
+        self.tape.data(self.idx)
+    }
+
+    pub fn relu(self) -> Var<'t> {
+        self.tape.push(Op::Relu(self.idx), self.data().max(0.0))
+    }
+
+    pub fn pow(self, rhs: f64) -> Var<'t> {
+        self.tape
+            .push(Op::PowConst(self.idx, rhs), self.data().powf(rhs))
+    }
+
+    pub fn pow_var(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary(
+            rhs,
+            Op::Pow(self.idx, rhs.idx),
+            self.data().powf(rhs.data()),
+        )
+    }
+
+    /// Reverse sweep from this node, returns the gradient of every node recorded before it.
+    pub fn backward(&self) -> Grads {
+        let nodes = self.tape.nodes.borrow();
+        let mut grads = vec![0.0; self.idx + 1];
+        grads[self.idx] = 1.0;
+
+        for i in (0..=self.idx).rev() {
+            let g = grads[i];
+            if g == 0.0 {
+                continue;
+            }
+            match nodes[i].op {
+                Op::Leaf => {}
+                Op::Add(a, b) => {
+                    grads[a] += g;
+                    grads[b] += g;
+                }
+                Op::Sub(a, b) => {
+                    grads[a] += g;
+                    grads[b] -= g;
+                }
+                Op::Mul(a, b) => {
+                    grads[a] += g * nodes[b].data;
+                    grads[b] += g * nodes[a].data;
+                }
+                Op::Div(a, b) => {
+                    let b_data = nodes[b].data;
+                    grads[a] += g / b_data;
+                    grads[b] -= g * nodes[a].data / (b_data * b_data);
+                }
+                Op::Pow(a, b) => {
+                    let (a_data, b_data) = (nodes[a].data, nodes[b].data);
+                    grads[a] += g * b_data * a_data.powf(b_data - 1.0);
+                    grads[b] += g * nodes[i].data * a_data.ln();
+                }
+                Op::PowConst(a, n) => {
+                    grads[a] += g * n * nodes[a].data.powf(n - 1.0);
+                }
+                Op::Relu(a) => {
+                    if nodes[a].data > 0.0 {
+                        grads[a] += g;
+                    }
+                }
+            }
+        }
+        Grads(grads)
+    }
+
+    fn binary(self, rhs: Var<'t>, op: Op, data: f64) -> Var<'t> {
+        assert!(
+            std::ptr::eq(self.tape, rhs.tape),
+            "vars belong to different tapes"
+        );
+        self.tape.push(op, data)
+    }
+}
+
+/// Gradients produced by `Var::backward`, indexed by `Var`.
+#[derive(Debug)]
+pub struct Grads(Vec<f64>);
+
+impl Grads {
+    /// Gradient of a var, zero if it was recorded after the root of the backward pass.
+    pub fn wrt(&self, var: Var<'_>) -> f64 {
+        self.0.get(var.idx).copied().unwrap_or(0.0)
+    }
+
+    /// Adds the gradients of `vars` into the matching `params`, see `Tape::load`.
+    pub fn accumulate(&self, params: &[Prev], vars: &[Var<'_>]) {
+        for (p, v) in params.iter().zip(vars.iter()) {
+            let value = p.value();
+            value.set_grad(value.grad() + self.wrt(*v));
+        }
+    }
+}
+
+impl<'t> Index<Var<'t>> for Grads {
+    type Output = f64;
+    fn index(&self, var: Var<'t>) -> &f64 {
+        &self.0[var.idx]
+    }
+}
+
+macro_rules! impl_binary_op {
+    ($trait:ident, $method:ident, $op:ident, $f:expr) => {
+        impl<'t> $trait for Var<'t> {
+            type Output = Var<'t>;
+            fn $method(self, rhs: Var<'t>) -> Var<'t> {
+                let data = $f(self.data(), rhs.data());
+                self.binary(rhs, Op::$op(self.idx, rhs.idx), data)
+            }
+        }
+
+        impl<'t> $trait<f64> for Var<'t> {
+            type Output = Var<'t>;
+            fn $method(self, rhs: f64) -> Var<'t> {
+                let rhs = self.tape.var(rhs);
+                self.$method(rhs)
+            }
+        }
+
+        impl<'t> $trait<Var<'t>> for f64 {
+            type Output = Var<'t>;
+            fn $method(self, rhs: Var<'t>) -> Var<'t> {
+                let lhs = rhs.tape.var(self);
+                lhs.$method(rhs)
+            }
+        }
+    };
+}
+
+impl_binary_op!(Add, add, Add, |a: f64, b: f64| a + b);
+impl_binary_op!(Sub, sub, Sub, |a: f64, b: f64| a - b);
+impl_binary_op!(Mul, mul, Mul, |a: f64, b: f64| a * b);
+impl_binary_op!(Div, div, Div, |a: f64, b: f64| a / b);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::Module;
+    use crate::{MLP, Value};
+
+    #[test]
+    fn tape_grad_test() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+        let b = tape.var(-3.0);
+        let c = (a * b + a.pow(2.0)) / b - (b - a).relu();
+        let grads = c.backward();
+
+        // c = a + a^2 / b, relu(b - a) is zero
+        assert!((c.data() - (2.0 - 4.0 / 3.0)).abs() < 1e-12);
+        assert!((grads[a] - (1.0 + 2.0 * 2.0 / -3.0)).abs() < 1e-12);
+        assert!((grads[b] - (-4.0 / 9.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn tape_matches_value_on_mlp() {
+        let mlp = MLP::new(2, &[4, 4, 1]);
+        let params = mlp.parameters();
+        let xs = [[0.5, -1.0], [1.5, 0.25], [-0.3, 0.8]];
+
+        let mut loss = Value::new(0.0);
+        for x in xs.iter() {
+            let out = mlp.forward(&vec![Value::new(x[0]), Value::new(x[1])]);
+            loss = &loss + &(&out[0] * &out[0]);
+        }
+        mlp.zero_grad();
+        loss.backward();
+        let value_grads = params.iter().map(|p| p.value().grad()).collect::<Vec<_>>();
+
+        let tape = Tape::new();
+        let vars = tape.load(&params);
+        let mut tape_loss = tape.var(0.0);
+        for x in xs.iter() {
+            let out = mlp.forward_tape(&vars, &[tape.var(x[0]), tape.var(x[1])]);
+            tape_loss = tape_loss + out[0] * out[0];
+        }
+        mlp.zero_grad();
+        tape_loss.backward().accumulate(&params, &vars);
+
+        assert!((loss.data() - tape_loss.data()).abs() < 1e-9);
+        for (p, g) in params.iter().zip(value_grads) {
+            assert!((p.value().grad() - g).abs() < 1e-9);
+        }
+    }
+}